@@ -0,0 +1,315 @@
+// Copyright 2023 The Dots Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Client library for skrecovery. [`SkRecoveryClient`] registers a user's
+//! secrets with the committee of dots nodes and recovers them with the user's
+//! password, without any node learning either.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), skrecovery::ClientError> {
+//! use skrecovery::{ClientConfig, SecretInput, SkRecoveryClient};
+//!
+//! let client = SkRecoveryClient::connect(&ClientConfig::default()).await?;
+//! client.register("alice", "default", &SecretInput::Text("my secret".to_owned()), "hunter2").await?;
+//! let secret = client.recover("alice", "default", "hunter2").await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+mod protocol;
+pub mod transport;
+pub mod util;
+
+pub use protocol::SecretInput;
+use transport::{Grpc, Transport};
+use util::{Committee, CommitteeStatus, FactorKind, FactorMeta, GuardianPolicy, NodeErrors, PendingRecovery, RecoveryState, Secret, SecretMeta, DEFAULT_FACTOR};
+
+/// Where the nodes are, in rank order.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientConfig {
+    pub nodes: Vec<String>,
+}
+
+impl Default for ClientConfig {
+    /// The five local nodes started by `start-n.sh`.
+    fn default() -> ClientConfig {
+        ClientConfig { nodes: (50050..50055).map(|port| format!("http://127.0.0.1:{}", port)).collect() }
+    }
+}
+
+impl ClientConfig {
+    /// Reads a config from a JSON file such as `{"nodes": ["http://10.0.0.1:50050", ...]}`.
+    pub fn load(path: &Path) -> Result<ClientConfig, ClientError> {
+        let data = std::fs::read(path).map_err(|e| ClientError::InvalidInput(format!("could not read {}: {}", path.display(), e)))?;
+        serde_json::from_slice(&data).map_err(|e| ClientError::InvalidInput(format!("invalid config {}: {}", path.display(), e)))
+    }
+}
+
+/// Why a request failed.
+#[derive(Debug)]
+pub enum ClientError {
+    /// A node could not be reached or did not answer.
+    Transport(String),
+    /// Nodes rejected the request, each error with the node's rank.
+    Nodes(NodeErrors),
+    /// The password or other factor was wrong, so nothing happened.
+    WrongPassword,
+    /// The request was rejected before anything was sent.
+    InvalidInput(String),
+    /// The nodes speak a different version of the protocol.
+    Incompatible(String),
+    /// Nodes that have to agree did not.
+    Disagreement(String),
+    /// The nodes at these ranks returned verification data that the rest of
+    /// the committee did not.
+    Inconsistent(Vec<usize>),
+    /// A node's output could not be decoded or combined.
+    Malformed(String),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Nodes(errors) => write!(f, "{}", errors),
+            ClientError::WrongPassword => f.write_str("password incorrect"),
+            ClientError::Inconsistent(ranks) => write!(f, "nodes at ranks {:?} reported different verification data", ranks),
+            ClientError::Transport(msg) | ClientError::InvalidInput(msg) | ClientError::Incompatible(msg) | ClientError::Disagreement(msg) | ClientError::Malformed(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl ClientError {
+    /// A stable name for the kind of error, for machine-readable output.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ClientError::Transport(_) => "transport",
+            ClientError::Nodes(_) => "nodes",
+            ClientError::WrongPassword => "wrong_password",
+            ClientError::InvalidInput(_) => "invalid_input",
+            ClientError::Incompatible(_) => "incompatible",
+            ClientError::Disagreement(_) => "disagreement",
+            ClientError::Inconsistent(_) => "inconsistent",
+            ClientError::Malformed(_) => "malformed",
+        }
+    }
+}
+
+impl Error for ClientError {}
+
+impl From<NodeErrors> for ClientError {
+    fn from(errors: NodeErrors) -> ClientError {
+        ClientError::Nodes(errors)
+    }
+}
+
+impl From<serde_json::Error> for ClientError {
+    fn from(e: serde_json::Error) -> ClientError {
+        ClientError::Malformed(e.to_string())
+    }
+}
+
+/// What the committee holds for a user.
+#[derive(Clone, Debug, Serialize)]
+pub struct UserStatus {
+    pub secrets: Vec<SecretMeta>,
+    pub factors: Vec<FactorMeta>,
+    pub guardians: Option<GuardianPolicy>,
+    pub recovery: RecoveryState,
+}
+
+/// A connection to the committee. Requests go to all nodes at once; the
+/// client splits what it sends and combines what comes back.
+pub struct SkRecoveryClient<T: Transport = Grpc> {
+    transport: T,
+}
+
+impl SkRecoveryClient<Grpc> {
+    pub async fn connect(config: &ClientConfig) -> Result<SkRecoveryClient<Grpc>, ClientError> {
+        Ok(SkRecoveryClient::new(Grpc::connect(&config.nodes).await?))
+    }
+}
+
+fn checked(correct: bool) -> Result<(), ClientError> {
+    correct.then_some(()).ok_or(ClientError::WrongPassword)
+}
+
+impl<T: Transport> SkRecoveryClient<T> {
+    pub fn new(transport: T) -> SkRecoveryClient<T> {
+        SkRecoveryClient { transport }
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Registers the user with a single secret, replacing the password and
+    /// any secrets they had before.
+    pub async fn register(&self, user_id: &str, label: &str, secret: &SecretInput, pwd: &str) -> Result<(), ClientError> {
+        protocol::upload_secret(&self.transport, user_id, label, secret, pwd).await
+    }
+
+    /// Adds another labeled secret under the user's password.
+    pub async fn add_secret(&self, user_id: &str, label: &str, secret: &SecretInput, pwd: &str) -> Result<(), ClientError> {
+        checked(protocol::add_secret(&self.transport, user_id, label, secret, pwd).await?)
+    }
+
+    /// Recovers a secret with the user's password. EC keys come back hex
+    /// encoded. Users with a recovery delay have to go through
+    /// `request_recovery` and `complete_recovery` instead.
+    pub async fn recover(&self, user_id: &str, label: &str, pwd: &str) -> Result<Secret<Vec<u8>>, ClientError> {
+        self.recover_with(user_id, label, DEFAULT_FACTOR, pwd, None).await
+    }
+
+    /// Recovers a secret with one of the user's other factors.
+    pub async fn recover_with_factor(&self, user_id: &str, label: &str, factor_id: &str, value: &str) -> Result<Secret<Vec<u8>>, ClientError> {
+        let guess = protocol::factor_guess(&self.transport, user_id, factor_id, value).await?;
+        self.recover_with(user_id, label, factor_id, &guess, None).await
+    }
+
+    /// Recovers a secret through a pending recovery that is ready.
+    pub async fn complete_recovery(&self, user_id: &str, label: &str, recovery_request: &str, pwd: &str) -> Result<Secret<Vec<u8>>, ClientError> {
+        self.recover_with(user_id, label, DEFAULT_FACTOR, pwd, Some(recovery_request)).await
+    }
+
+    async fn recover_with(&self, user_id: &str, label: &str, factor: &str, guess: &str, recovery_request: Option<&str>) -> Result<Secret<Vec<u8>>, ClientError> {
+        let secret = protocol::recover_sk(&self.transport, user_id, label, factor, guess, recovery_request).await?;
+        if secret.is_empty() {
+            return Err(ClientError::WrongPassword);
+        }
+        Ok(secret)
+    }
+
+    /// Recovers a secret with guardian approvals of `recovery_request`
+    /// instead of a password. Each request works once. With a recovery
+    /// delay, the nodes refuse the first call and open a pending recovery;
+    /// the same call succeeds once it is ready.
+    pub async fn recover_with_guardians(&self, user_id: &str, label: &str, recovery_request: &str, approvals: &[Vec<u8>]) -> Result<Secret<Vec<u8>>, ClientError> {
+        let secret = protocol::recover_with_guardians(&self.transport, user_id, label, recovery_request, approvals).await?;
+        if secret.is_empty() {
+            return Err(ClientError::Disagreement("the recovered secret does not match what the nodes stored".to_owned()));
+        }
+        Ok(secret)
+    }
+
+    /// Signs a message with a registered EC key without reconstructing it.
+    /// Returns the Schnorr signature and the public key it verifies under.
+    pub async fn sign(&self, user_id: &str, label: &str, msg: &[u8], pwd: &str) -> Result<(Vec<u8>, Vec<u8>), ClientError> {
+        protocol::sign(&self.transport, user_id, label, msg, pwd).await?.ok_or(ClientError::WrongPassword)
+    }
+
+    /// Decrypts an ECIES ciphertext addressed to a registered EC key.
+    pub async fn decrypt(&self, user_id: &str, label: &str, ciphertext: &[u8], pwd: &str) -> Result<Secret<Vec<u8>>, ClientError> {
+        protocol::decrypt(&self.transport, user_id, label, ciphertext, pwd).await?.ok_or(ClientError::WrongPassword)
+    }
+
+    pub async fn change_password(&self, user_id: &str, old_pwd: &str, new_pwd: &str) -> Result<(), ClientError> {
+        checked(protocol::change_password(&self.transport, user_id, old_pwd, new_pwd).await?)
+    }
+
+    /// Removes the user and everything they stored.
+    pub async fn delete(&self, user_id: &str, pwd: &str) -> Result<(), ClientError> {
+        checked(protocol::delete_user(&self.transport, user_id, pwd).await?)
+    }
+
+    /// Everything the committee holds for the user but their secrets' values.
+    pub async fn status(&self, user_id: &str) -> Result<UserStatus, ClientError> {
+        Ok(UserStatus {
+            secrets: self.list_secrets(user_id).await?,
+            factors: self.list_factors(user_id).await?,
+            guardians: self.list_guardians(user_id).await?,
+            recovery: self.recovery_status(user_id).await?,
+        })
+    }
+
+    pub async fn list_secrets(&self, user_id: &str) -> Result<Vec<SecretMeta>, ClientError> {
+        protocol::list_secrets(&self.transport, user_id).await
+    }
+
+    /// Adds a recovery factor after the committee has checked `auth_factor`.
+    pub async fn add_factor(&self, user_id: &str, factor_id: &str, kind: FactorKind, value: &str, auth_factor: &str, auth_value: &str) -> Result<(), ClientError> {
+        let auth_value = protocol::factor_guess(&self.transport, user_id, auth_factor, auth_value).await?;
+        checked(protocol::add_factor(&self.transport, user_id, factor_id, kind, value, auth_factor, &auth_value).await?)
+    }
+
+    /// Removes a recovery factor after the committee has checked
+    /// `auth_factor`, which may be the same one.
+    pub async fn revoke_factor(&self, user_id: &str, factor_id: &str, auth_factor: &str, auth_value: &str) -> Result<(), ClientError> {
+        let auth_value = protocol::factor_guess(&self.transport, user_id, auth_factor, auth_value).await?;
+        checked(protocol::revoke_factor(&self.transport, user_id, factor_id, auth_factor, &auth_value).await?)
+    }
+
+    pub async fn list_factors(&self, user_id: &str) -> Result<Vec<FactorMeta>, ClientError> {
+        protocol::list_factors(&self.transport, user_id).await
+    }
+
+    pub async fn set_guardians(&self, user_id: &str, policy: &GuardianPolicy, pwd: &str) -> Result<(), ClientError> {
+        checked(protocol::set_guardians(&self.transport, user_id, policy, pwd).await?)
+    }
+
+    pub async fn list_guardians(&self, user_id: &str) -> Result<Option<GuardianPolicy>, ClientError> {
+        protocol::list_guardians(&self.transport, user_id).await
+    }
+
+    /// Sets how long recoveries wait between the password check and the
+    /// release of shares. Returns when the new delay takes effect.
+    pub async fn set_recovery_delay(&self, user_id: &str, seconds: u64, pwd: &str) -> Result<u64, ClientError> {
+        protocol::set_recovery_delay(&self.transport, user_id, seconds, pwd).await?.ok_or(ClientError::WrongPassword)
+    }
+
+    pub async fn request_recovery(&self, user_id: &str, label: &str, pwd: &str) -> Result<PendingRecovery, ClientError> {
+        protocol::request_recovery(&self.transport, user_id, label, pwd).await?.ok_or(ClientError::WrongPassword)
+    }
+
+    /// Cancels one pending recovery, or all of them and any pending lowering
+    /// of the delay.
+    pub async fn cancel_recovery(&self, user_id: &str, recovery_request: Option<&str>, pwd: &str) -> Result<(), ClientError> {
+        checked(protocol::cancel_recovery(&self.transport, user_id, recovery_request, pwd).await?)
+    }
+
+    pub async fn recovery_status(&self, user_id: &str) -> Result<RecoveryState, ClientError> {
+        protocol::recovery_status(&self.transport, user_id).await
+    }
+
+    /// Replaces a text secret, keeping the password. Returns the new version.
+    pub async fn rotate(&self, user_id: &str, label: &str, new_sk: &str, pwd: &str) -> Result<u64, ClientError> {
+        protocol::rotate_sk(&self.transport, user_id, label, new_sk, pwd).await?.ok_or(ClientError::WrongPassword)
+    }
+
+    /// Seeds the PRGs the committee members share. Run once per deployment.
+    pub async fn seed_prgs(&self) -> Result<(), ClientError> {
+        protocol::seed_prgs(&self.transport).await
+    }
+
+    /// What every node reports about the committee, indexed by rank.
+    pub async fn node_statuses(&self) -> Result<Vec<CommitteeStatus>, ClientError> {
+        protocol::committee_statuses(&self.transport).await
+    }
+
+    /// The audit log of every node, in rank order, as raw JSON lines.
+    pub async fn audit_logs(&self) -> Result<Vec<Vec<u8>>, ClientError> {
+        protocol::fetch_audit_logs(&self.transport).await
+    }
+
+    /// Moves every user to a new committee of the nodes at `members`. Returns
+    /// the new committee and how many users it holds.
+    pub async fn reshare(&self, threshold: usize, members: Vec<usize>) -> Result<(Committee, usize), ClientError> {
+        protocol::reshare(&self.transport, threshold, members).await
+    }
+}
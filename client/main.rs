@@ -12,258 +12,906 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::env;
+// Command line front end of the client library. Passwords and secrets are
+// never taken as arguments, where they would end up in shell history and ps:
+// they are prompted for without echo, or read from files or stdin.
+
 use std::error::Error;
-use std::iter;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use blake2::{Blake2b512, Digest};
-use dotspb::dec_exec::dec_exec_client::DecExecClient;
-use futures::future;
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use elliptic_curve::Field;
 use p256::Scalar;
 use rand::prelude::*;
 use rand_chacha::ChaCha20Rng;
-use tonic::transport::Channel;
-use tonic::Request;
+use serde::Serialize;
+use serde_json::{json, Value};
 use uuid::Uuid;
-use vsss_rs::{Shamir, Share};
+use zeroize::Zeroizing;
+
+use skrecovery::util::*;
+use skrecovery::transport::{Call, Grpc, Recorded};
+use skrecovery::{ClientConfig, ClientError, SecretInput, SkRecoveryClient, UserStatus};
+
+/// Stores secrets with a committee of dots nodes and recovers them with a
+/// password, other factors or guardians.
+#[derive(Parser)]
+#[command(name = "client", version)]
+struct Cli {
+    /// JSON file listing the nodes' addresses in rank order; the five local
+    /// nodes of the README by default
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Print recovered secrets instead of only their size
+    #[arg(long, global = true)]
+    show_secrets: bool,
+
+    /// Read passwords from this file, one per line in the order they are
+    /// asked for, instead of prompting for them
+    #[arg(long, global = true)]
+    password_file: Option<PathBuf>,
+
+    /// Print text for people, or a single JSON document for scripts
+    #[arg(long, global = true, value_enum, default_value_t = Format::Text)]
+    output: Format,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Text,
+    Json,
+}
 
-#[path = "../util.rs"]
-mod util;
-use util::*;
+#[derive(Clone, Copy, ValueEnum)]
+enum Kind {
+    Text,
+    EcKey,
+    Bytes,
+}
 
-const APP_NAME: &str = "skrecovery";
+#[derive(Args)]
+struct SecretArgs {
+    /// What the secret is; an EC key is a P-256 private key in hex
+    #[arg(long, value_enum, default_value_t = Kind::Text)]
+    kind: Kind,
 
-fn uuid_to_uuidpb(id: Uuid) -> dotspb::dec_exec::Uuid {
-    dotspb::dec_exec::Uuid {
-        hi: (id.as_u128() >> 64) as u64,
-        lo: id.as_u128() as u64,
+    /// Read the secret from this file, or from stdin for "-", instead of
+    /// prompting for it
+    #[arg(long)]
+    secret_file: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+#[command(rename_all = "snake_case")]
+enum Command {
+    /// Seed the PRGs the committee members share; run once per deployment
+    SeedPrgs,
+    /// Register a user with one secret and a password, replacing anything
+    /// stored for them before
+    Register {
+        user: String,
+        #[arg(long, default_value = DEFAULT_LABEL)]
+        label: String,
+        #[command(flatten)]
+        secret: SecretArgs,
+    },
+    /// Store another secret under the user's password
+    AddSecret {
+        user: String,
+        label: String,
+        #[command(flatten)]
+        secret: SecretArgs,
+    },
+    /// List the user's secrets without their values
+    ListSecrets {
+        user: String,
+    },
+    /// Recover a secret with the password
+    RecoverSk {
+        user: String,
+        #[arg(long, default_value = DEFAULT_LABEL)]
+        label: String,
+        /// Write the secret to this file
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Recover all of the user's secrets with the password
+    RecoverAll {
+        user: String,
+        /// Write each secret to a file named after its label here
+        #[arg(long = "out-dir")]
+        out_dir: Option<PathBuf>,
+    },
+    /// Sign a message with a registered EC key, without reconstructing it
+    Sign {
+        user: String,
+        message: String,
+        #[arg(long, default_value = DEFAULT_LABEL)]
+        label: String,
+    },
+    /// Encrypt a file to a P-256 public key given in hex; needs no nodes
+    Encrypt {
+        public_key: String,
+        input: PathBuf,
+        output: PathBuf,
+    },
+    /// Decrypt a file encrypted to a registered EC key
+    Decrypt {
+        user: String,
+        input: PathBuf,
+        #[arg(long)]
+        out: Option<PathBuf>,
+        #[arg(long, default_value = DEFAULT_LABEL)]
+        label: String,
+    },
+    /// Change the password; asks for the old one, then the new one twice
+    ChangePassword {
+        user: String,
+    },
+    /// Remove the user and everything stored for them
+    DeleteUser {
+        user: String,
+    },
+    /// Replace a text secret, keeping the password
+    RotateSk {
+        user: String,
+        #[arg(long, default_value = DEFAULT_LABEL)]
+        label: String,
+        #[arg(long = "secret-file")]
+        secret_file: Option<PathBuf>,
+    },
+    /// Add a recovery factor; backup codes are generated and printed once
+    AddFactor {
+        user: String,
+        factor_id: String,
+        #[arg(value_parser = parse_factor_kind)]
+        kind: FactorKind,
+        /// The factor to authorize the change with
+        #[arg(long = "auth-factor", default_value = DEFAULT_FACTOR)]
+        auth_factor: String,
+    },
+    /// Remove a recovery factor
+    RevokeFactor {
+        user: String,
+        factor_id: String,
+        #[arg(long = "auth-factor", default_value = DEFAULT_FACTOR)]
+        auth_factor: String,
+    },
+    /// List the user's recovery factors
+    ListFactors {
+        user: String,
+    },
+    /// Recover a secret with a factor other than the password
+    RecoverWithFactor {
+        user: String,
+        factor_id: String,
+        #[arg(long, default_value = DEFAULT_LABEL)]
+        label: String,
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Generate a key pair for a guardian; needs no nodes
+    GuardianKeygen,
+    /// Let THRESHOLD of the guardians, given by public keys, approve recoveries
+    SetGuardians {
+        user: String,
+        threshold: usize,
+        #[arg(value_delimiter = ',')]
+        guardians: Vec<String>,
+    },
+    /// Show the user's guardians and how many have to approve
+    ListGuardians {
+        user: String,
+    },
+    /// Start a recovery for guardians to approve; needs no nodes
+    GuardianRequest {
+        user: String,
+        #[arg(long, default_value = DEFAULT_LABEL)]
+        label: String,
+    },
+    /// Approve a recovery as a guardian; needs no nodes
+    GuardianApprove {
+        user: String,
+        request_id: String,
+        #[arg(long, default_value = DEFAULT_LABEL)]
+        label: String,
+        /// Read the guardian's private key from this file instead of
+        /// prompting for it
+        #[arg(long = "key-file")]
+        key_file: Option<PathBuf>,
+    },
+    /// Recover a secret with the approvals, in hex, of enough guardians
+    RecoverWithGuardians {
+        user: String,
+        request_id: String,
+        #[arg(value_delimiter = ',')]
+        approvals: Vec<String>,
+        #[arg(long, default_value = DEFAULT_LABEL)]
+        label: String,
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Make recoveries wait this long after the password check
+    SetRecoveryDelay {
+        user: String,
+        seconds: u64,
+    },
+    /// Start a delayed recovery with the password
+    RequestRecovery {
+        user: String,
+        #[arg(long, default_value = DEFAULT_LABEL)]
+        label: String,
+    },
+    /// Recover a secret once its delayed recovery is ready
+    CompleteRecovery {
+        user: String,
+        request_id: String,
+        #[arg(long, default_value = DEFAULT_LABEL)]
+        label: String,
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Cancel one pending recovery, or all of them and any lowering of the delay
+    CancelRecovery {
+        user: String,
+        request_id: Option<String>,
+    },
+    /// Show the recovery delay and any pending recoveries
+    RecoveryStatus {
+        user: String,
+    },
+    /// Show everything stored for the user but their secrets' values
+    Status {
+        user: String,
+    },
+    /// Show the committee and users every node holds
+    CommitteeStatus,
+    /// Check every node's audit log and compare them
+    AuditLog {
+        /// Save the logs here as audit<rank>.log
+        #[arg(long = "out-dir")]
+        out_dir: Option<PathBuf>,
+    },
+    /// Move every user to a new committee of the nodes at MEMBERS
+    Reshare {
+        threshold: usize,
+        #[arg(value_delimiter = ',')]
+        members: Vec<usize>,
+    },
+}
+
+fn parse_factor_kind(kind: &str) -> Result<FactorKind, String> {
+    match kind {
+        "password" => Ok(FactorKind::Password),
+        "backup_code" => Ok(FactorKind::BackupCode),
+        "security_questions" => Ok(FactorKind::SecurityQuestions),
+        kind => Err(format!("unknown kind of factor {}, expected password, backup_code or security_questions", kind)),
     }
 }
 
-async fn seed_prgs(clients: &mut [DecExecClient<Channel>]) -> Result<(), Box<dyn Error>> {
-    let request_id = Uuid::new_v4();
-    future::join_all(
-            clients.iter_mut()
-                .map(|client|
-                    client.exec(Request::new(dotspb::dec_exec::App {
-                        app_name: APP_NAME.to_owned(),
-                        app_uid: 0,
-                        request_id: Some(uuid_to_uuidpb(request_id)),
-                        client_id: "".to_owned(),
-                        func_name: "seed_prgs".to_owned(),
-                        in_files: vec![],
-                        out_files: vec![],
-                        args: vec![],
-                    }))
-                )
-        )
-        .await
-        .into_iter()
-        .collect::<Result<Vec<_>, _>>()?;
-
-    Ok(())
-}
-
-async fn upload_sk_and_pwd(clients: &mut [DecExecClient<Channel>], id: &str, sk: &str, pwd: &str) -> Result<(), Box<dyn Error>> {
-    let rng = &mut ChaCha20Rng::from_entropy();
-    let field_elts = sk_to_field_elts(&sk);
-    let mut shares_vec = Vec::new();
-    for _ in 0..NUM_SERVERS {
-        shares_vec.push(Vec::new());
+// Passwords come from hidden prompts on the terminal, or from the lines of
+// --password-file in the order they are asked for.
+struct Passwords {
+    file: Option<std::vec::IntoIter<Zeroizing<String>>>,
+}
+
+impl Passwords {
+    fn new(path: Option<&Path>) -> Result<Passwords, Box<dyn Error>> {
+        let file = match path {
+            Some(path) => {
+                let text = Zeroizing::new(fs::read_to_string(path)?);
+                Some(text.lines().map(|line| Zeroizing::new(line.to_owned())).collect::<Vec<_>>().into_iter())
+            }
+            None => None,
+        };
+        Ok(Passwords { file })
     }
 
-    for nzs in field_elts.as_slice() {
-        // 32 for field size, 1 for identifier = 33
-        let res = Shamir::<THRESHOLD, NUM_SERVERS>::split_secret::<Scalar, ChaCha20Rng, 33>(
-            *nzs.as_ref(),
-            rng,
-        )
-        .unwrap();
-        for i in 0..NUM_SERVERS {
-            shares_vec[i].push(res[i]);
+    fn ask(&mut self, prompt: &str) -> Result<Zeroizing<String>, Box<dyn Error>> {
+        match &mut self.file {
+            Some(lines) => lines.next().ok_or_else(|| invalid_input(format!("the password file has no line left for: {}", prompt))),
+            None => prompt_hidden(prompt),
         }
     }
-    let sk_shares: Vec<Vec<u8>> = shares_vec
-        .iter()
-        .map(|share| serde_json::to_vec(share).unwrap())
-        .collect();
 
-    let pwd_nzs = string_hash_to_nzs(&pwd);
-    let pwd_shares: Vec<Vec<u8>> = Shamir::<THRESHOLD, NUM_SERVERS>::split_secret::<Scalar, ChaCha20Rng, 33>(
-        *pwd_nzs.as_ref(),
-        rng,
-    )
-        .unwrap()
-        .iter()
-        .map(|share| share.as_ref().to_vec())
-        .collect();
+    // A new password, which has to be typed twice when prompted for.
+    fn ask_new(&mut self, prompt: &str) -> Result<Zeroizing<String>, Box<dyn Error>> {
+        let pwd = self.ask(prompt)?;
+        if self.file.is_none() && *self.ask(&format!("{} again", prompt))? != *pwd {
+            return Err(invalid_input("the entries do not match"));
+        }
+        Ok(pwd)
+    }
+}
+
+fn invalid_input(msg: impl Into<String>) -> Box<dyn Error> {
+    Box::new(ClientError::InvalidInput(msg.into()))
+}
 
-    // TODO: you wanna compress these into the same file? maybe take a look at serde, or maybe that's not necessary.
-    // idgaf it's pretty inconsequential.
-    let salt = rng.gen::<[u8; 32]>();
-    let mut hasher = Blake2b512::new();
-    hasher.update(salt);
-    for nzs in field_elts.as_slice() {
-        hasher.update(nzs.to_bytes());
+// A hidden prompt on the terminal.
+fn prompt_hidden(prompt: &str) -> Result<Zeroizing<String>, Box<dyn Error>> {
+    rpassword::prompt_password(format!("{}: ", prompt))
+        .map(Zeroizing::new)
+        .map_err(|e| io::Error::new(e.kind(), format!("could not prompt for {} ({}), give it in a file instead", prompt.to_lowercase(), e)).into())
+}
+
+// The whole of a file, or of stdin for "-".
+fn read_input(path: &Path) -> Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
+    let mut data = Zeroizing::new(Vec::new());
+    if path == Path::new("-") {
+        io::stdin().read_to_end(&mut data)?;
+    } else {
+        *data = fs::read(path)?;
     }
-    let hash = hasher.finalize().to_vec();
-
-    let request_id = Uuid::new_v4();
-    future::join_all(
-            iter::zip(clients, iter::zip(sk_shares, pwd_shares))
-                .map(|(client, (sk_share, pwd_share))|
-                    client.exec(Request::new(dotspb::dec_exec::App {
-                        app_name: APP_NAME.to_owned(),
-                        app_uid: 0,
-                        request_id: Some(uuid_to_uuidpb(request_id)),
-                        client_id: "".to_owned(),
-                        func_name: "upload_sk_and_pwd".to_owned(),
-                        in_files: vec![],
-                        out_files: vec![],
-                        args: vec![id.as_bytes().to_owned(), sk_share, pwd_share, salt.to_vec(), hash.clone()],
-                    }))
-                )
-        )
-        .await
-        .into_iter()
-        .collect::<Result<Vec<_>, _>>()?;
-
-    Ok(())
-}
-
-fn compute_pwd_guess(pwd_guess: &str) -> Vec<Vec<u8>> {
-    let rng = &mut ChaCha20Rng::from_entropy();
-    let pwd_guess_nzs = string_hash_to_nzs(&pwd_guess);
-    let pwd_guess_shares = Shamir::<{ THRESHOLD }, NUM_SERVERS>::split_secret::<
-        Scalar,
-        ChaCha20Rng,
-        33,
-    >(*pwd_guess_nzs.as_ref(), rng)
-    .unwrap();
-    pwd_guess_shares.map(|x| x.as_ref().to_vec()).to_vec()
-}
-
-fn aggregate_sk(outputs: &[&[u8]]) -> Vec<u8> {
-    let deserialized: Vec<(Vec<Vec<u8>>, Vec<u8>, Vec<u8>)> = outputs
-        .iter()
-        .map(|x| serde_json::from_slice::<(Vec<Vec<u8>>, Vec<u8>, Vec<u8>)>(x).unwrap())
-        .collect();
-    let sk_shares: Vec<&[Vec<u8>]> = deserialized.iter().map(|x| x.0.as_slice()).collect();
-    let salts: Vec<&[u8]> = deserialized.iter().map(|x| x.1.as_slice()).collect();
-    let hashes: Vec<&[u8]> = deserialized.iter().map(|x| x.2.as_slice()).collect();
-    // get back (2t, n) shares bc of multiplication
-    const RECOVER_THRESHOLD: usize = THRESHOLD*2;
-    let num_chunks = sk_shares[0].len();
-    let mut sk_scalars = Vec::new();
-    for i in 0..num_chunks {
-        let mut scalars = Vec::new();
-        for vec in sk_shares.as_slice() {
-            scalars.push(Share::try_from(vec[i].as_slice()).unwrap());
-        }
-        let res = Shamir::<RECOVER_THRESHOLD, NUM_SERVERS>::combine_shares::<Scalar, 33>(&scalars);
-        assert!(res.is_ok());
-        let sk_scalar = res.unwrap();
-        sk_scalars.push(sk_scalar);
+    Ok(data)
+}
+
+// Text from a file without its trailing newline, or from a hidden prompt,
+// confirmed when `confirm` is set.
+fn read_text(path: Option<&Path>, prompt: &str, confirm: bool) -> Result<Zeroizing<String>, Box<dyn Error>> {
+    let Some(path) = path else {
+        let text = prompt_hidden(prompt)?;
+        if confirm && *prompt_hidden(&format!("{} again", prompt))? != *text {
+            return Err(invalid_input("the entries do not match"));
+        }
+        return Ok(text);
+    };
+    let data = read_input(path)?;
+    let text = std::str::from_utf8(&data).map_err(|_| invalid_input(format!("{} is not UTF-8 text", path.display())))?;
+    Ok(Zeroizing::new(text.trim_end_matches(['\r', '\n']).to_owned()))
+}
+
+fn read_secret(args: &SecretArgs) -> Result<SecretInput, Box<dyn Error>> {
+    Ok(match args.kind {
+        Kind::Text => SecretInput::Text((*read_text(args.secret_file.as_deref(), "Secret", true)?).clone()),
+        Kind::EcKey => SecretInput::EcKey((*read_text(args.secret_file.as_deref(), "EC private key (hex)", true)?).clone()),
+        Kind::Bytes => {
+            let path = args.secret_file.as_deref().ok_or_else(|| invalid_input("--kind bytes needs --secret-file"))?;
+            SecretInput::Bytes(read_input(path)?.to_vec())
+        }
+    })
+}
+
+// A random code for the user to print and keep, in groups of four characters.
+fn generate_backup_code(rng: &mut ChaCha20Rng) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+    (0..4)
+        .map(|_| (0..4).map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char).collect::<String>())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+
+// Exit statuses by class of failure, which scripts can rely on. Bad usage
+// exits with 2, from clap.
+const EXIT_ERROR: i32 = 1;
+const EXIT_WRONG_PASSWORD: i32 = 3;
+const EXIT_UNREACHABLE: i32 = 4;
+const EXIT_NODE_ERRORS: i32 = 5;
+const EXIT_INVALID_INPUT: i32 = 6;
+const EXIT_INCOMPATIBLE: i32 = 7;
+const EXIT_INTEGRITY: i32 = 8;
+const EXIT_AUDIT: i32 = 9;
+
+// Version of the JSON document printed with --output json, raised when
+// fields change meaning or go away.
+const REPORT_VERSION: u32 = 1;
+
+// Prints a line of text output. JSON output carries the same in the result.
+macro_rules! say {
+    ($format:expr, $($arg:tt)*) => {
+        if $format == Format::Text {
+            println!($($arg)*);
+        }
+    };
+}
+
+// A failure that comes with its own message for text output, such as the
+// one for a wrong password, and with whatever the command found out before
+// failing.
+#[derive(Debug)]
+struct Failure {
+    code: &'static str,
+    exit_code: i32,
+    message: String,
+    result: Value,
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
     }
+}
 
-    //Check that all salts and hashes are the same
+impl Error for Failure {}
 
-    if verify_sk_hash(&salts, &hashes, sk_scalars.as_slice()) {
-        field_elts_to_string(sk_scalars.as_slice()).into_bytes().to_vec()
+// A wrong password fails the command with the message given.
+fn rejected<T>(res: Result<T, ClientError>, message: &str) -> Result<T, Box<dyn Error>> {
+    match res {
+        Err(ClientError::WrongPassword) => Err(Box::new(Failure {
+            code: ClientError::WrongPassword.code(),
+            exit_code: EXIT_WRONG_PASSWORD,
+            message: message.to_owned(),
+            result: Value::Null,
+        })),
+        res => Ok(res?),
     }
-    else {
-        Vec::new()
+}
+
+// The code and exit status of an error.
+fn classify(e: &(dyn Error + 'static)) -> (&'static str, i32) {
+    if let Some(failure) = e.downcast_ref::<Failure>() {
+        return (failure.code, failure.exit_code);
     }
-    
-}
-
-async fn recover_sk(clients: &mut [DecExecClient<Channel>], id: &str, pwd_guess: &str) -> Result<Vec<u8>, Box<dyn Error>> {
-    let pwd_guess_shares = compute_pwd_guess(pwd_guess);
-
-    let request_id = Uuid::new_v4();
-    let res = future::join_all(
-            iter::zip(clients, pwd_guess_shares)
-                .map(|(client, pwd_guess_share)|
-                    client.exec(Request::new(dotspb::dec_exec::App {
-                        app_name: APP_NAME.to_owned(),
-                        app_uid: 0,
-                        request_id: Some(uuid_to_uuidpb(request_id)),
-                        client_id: "".to_owned(),
-                        func_name: "skrecovery".to_owned(),
-                        in_files: vec![],
-                        out_files: vec![],
-                        args: vec![id.as_bytes().to_owned(), pwd_guess_share],
-                    }))
-                )
-        )
-        .await
-        .into_iter()
-        .collect::<Result<Vec<_>, _>>()?
-        .into_iter()
-        .map(|res| res.into_inner())
-        .collect::<Vec<_>>();
-    let outputs: Vec<&[u8]> = res
-        .iter()
-        .map(|res| res.output.as_slice())
-        .collect();
+    match e.downcast_ref::<ClientError>() {
+        Some(e) => (e.code(), match e {
+            ClientError::WrongPassword => EXIT_WRONG_PASSWORD,
+            ClientError::Transport(_) => EXIT_UNREACHABLE,
+            ClientError::Nodes(_) => EXIT_NODE_ERRORS,
+            ClientError::InvalidInput(_) => EXIT_INVALID_INPUT,
+            ClientError::Incompatible(_) => EXIT_INCOMPATIBLE,
+            ClientError::Disagreement(_) | ClientError::Inconsistent(_) | ClientError::Malformed(_) => EXIT_INTEGRITY,
+        }),
+        None if e.is::<io::Error>() => ("io", EXIT_ERROR),
+        None => ("error", EXIT_ERROR),
+    }
+}
 
-    let s = aggregate_sk(&outputs);
+// Reports a recovered secret, writing it to out_path if given and printing it
+// only if asked to.
+fn report_recovered(format: Format, what: &str, label: &str, s: &[u8], out_path: Option<&Path>, show_secrets: bool) -> Result<Value, Box<dyn Error>> {
+    if let Some(out_path) = out_path {
+        fs::write(out_path, s)?;
+        say!(format, "Recovered {} written to {}", what, out_path.display());
+    } else if show_secrets {
+        say!(format, "Recovered {}: {}", what, String::from_utf8_lossy(s));
+    } else {
+        say!(format, "Recovered {} ({} bytes), pass --show-secrets to print it or give an output path", what, s.len());
+    }
+    Ok(json!({
+        "label": label,
+        "size": s.len(),
+        "written_to": out_path,
+        "secret_hex": (show_secrets && out_path.is_none()).then(|| hex::encode(s)),
+    }))
+}
 
-    Ok(s)
+fn print_secrets(format: Format, secrets: &[SecretMeta]) {
+    for meta in secrets {
+        say!(format, "{}: {:?}, {} bytes, created {}", meta.label, meta.kind, meta.size, meta.created);
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = env::args().collect();
-    let cmd = &args[1];
-    let node_addrs = [
-        "http://127.0.0.1:50050",
-        "http://127.0.0.1:50051",
-        "http://127.0.0.1:50052",
-        "http://127.0.0.1:50053",
-        "http://127.0.0.1:50054",
-    ];
-
-    let mut clients = future::join_all(
-            node_addrs
+fn print_factors(format: Format, factors: &[FactorMeta]) {
+    for factor in factors {
+        say!(format, "{}: {:?}, created {}", factor.id, factor.kind, factor.created);
+    }
+}
+
+fn print_guardians(format: Format, user: &str, guardians: &Option<GuardianPolicy>) {
+    match guardians {
+        Some(policy) => {
+            say!(format, "{} of:", policy.threshold);
+            for guardian in &policy.guardians {
+                say!(format, "{}", guardian);
+            }
+        }
+        None => say!(format, "User {} has no guardians", user),
+    }
+}
+
+fn print_recovery_state(format: Format, state: &RecoveryState) {
+    say!(format, "Recovery delay: {} seconds", state.delay);
+    if let Some(change) = &state.lowering {
+        say!(format, "Lowering to {} seconds at {}", change.seconds, change.effective_at);
+    }
+    for pending in &state.pending {
+        say!(format, "{}: {}, requested {}, ready at {}", pending.request_id, pending.label, pending.created, pending.ready_at);
+    }
+}
+
+// Runs the command and returns its result for JSON output. Requests made to
+// the nodes are left in `calls`, whether the command succeeded or not.
+async fn run(cli: Cli, calls: &mut Vec<Call>) -> Result<Value, Box<dyn Error>> {
+    let format = cli.output;
+    let mut passwords = Passwords::new(cli.password_file.as_deref())?;
+
+    // commands that need no nodes
+    match &cli.command {
+        Command::Encrypt { public_key, input, output } => {
+            let pubkey = hex::decode(public_key).map_err(|e| invalid_input(format!("bad public key: {}", e)))?;
+            let ciphertext = ecies_encrypt(&pubkey, &fs::read(input)?, &mut ChaCha20Rng::from_entropy())?;
+            fs::write(output, ciphertext)?;
+            say!(format, "Encrypted {} to {}", input.display(), output.display());
+            return Ok(json!({ "input": input, "output": output }));
+        }
+        Command::GuardianKeygen => {
+            let sk = Zeroizing::new(Scalar::random(&mut ChaCha20Rng::from_entropy()));
+            let (private_key, public_key) = (hex::encode(sk.to_bytes()), hex::encode(ec_public_key(&sk)));
+            say!(format, "Guardian private key: {}", private_key);
+            say!(format, "Guardian public key: {}", public_key);
+            return Ok(json!({ "private_key": private_key, "public_key": public_key }));
+        }
+        Command::GuardianRequest { user, label } => {
+            let request_id = Uuid::now_v7();
+            say!(format, "Recovery request ID: {}", request_id);
+            say!(format, "Ask guardians to run: guardian_approve {} <request_id> --label {}", user, label);
+            return Ok(json!({ "request_id": request_id.to_string() }));
+        }
+        Command::GuardianApprove { user, request_id, label, key_file } => {
+            let hex_key = read_text(key_file.as_deref(), "Guardian private key", false)?;
+            let sk = Zeroizing::new(ec_key_from_hex(&hex_key).map_err(|e| invalid_input(e.to_string()))?);
+            let msg = guardian_approval_message(user, label, request_id);
+            let approval = hex::encode(schnorr_sign(&sk, &msg, &mut ChaCha20Rng::from_entropy()));
+            say!(format, "Approval: {}", approval);
+            return Ok(json!({ "approval": approval }));
+        }
+        _ => {}
+    }
+
+    let config = match &cli.config {
+        Some(path) => ClientConfig::load(path)?,
+        None => ClientConfig::default(),
+    };
+    let client = SkRecoveryClient::new(Recorded::new(Grpc::connect(&config.nodes).await?));
+    let res = run_with_nodes(cli.command, &client, &mut passwords, format, cli.show_secrets).await;
+    *calls = client.transport().calls();
+    res
+}
+
+async fn run_with_nodes(command: Command, client: &SkRecoveryClient<Recorded<Grpc>>, passwords: &mut Passwords, format: Format, show_secrets: bool) -> Result<Value, Box<dyn Error>> {
+    Ok(match command {
+        Command::SeedPrgs => {
+            client.seed_prgs().await?;
+            json!({})
+        }
+        Command::Register { user, label, secret } => {
+            let input = read_secret(&secret)?;
+            let pwd = passwords.ask_new("New password")?;
+            say!(format, "Uploading secret {} for user {}", label, user);
+            client.register(&user, &label, &input, &pwd).await?;
+            json!({ "user": user, "label": label })
+        }
+        Command::AddSecret { user, label, secret } => {
+            let input = read_secret(&secret)?;
+            let pwd = passwords.ask("Password")?;
+            rejected(client.add_secret(&user, &label, &input, &pwd).await, "Password incorrect!")?;
+            say!(format, "Added secret {} for user {}", label, user);
+            json!({ "user": user, "label": label })
+        }
+        Command::ListSecrets { user } => {
+            let secrets = client.list_secrets(&user).await?;
+            print_secrets(format, &secrets);
+            json!({ "secrets": secrets })
+        }
+        Command::RecoverSk { user, label, out } => {
+            let pwd_guess = passwords.ask("Password")?;
+            say!(format, "Recovering secret {} for user {}", label, user);
+            let s = rejected(client.recover(&user, &label, &pwd_guess).await, "Recovered sk incorrect!")?;
+            report_recovered(format, "sk", &label, &s, out.as_deref(), show_secrets)?
+        }
+        Command::RecoverAll { user, out_dir } => {
+            let pwd_guess = passwords.ask("Password")?;
+            let mut secrets = Vec::new();
+            for meta in client.list_secrets(&user).await? {
+                let s = rejected(client.recover(&user, &meta.label, &pwd_guess).await, "Recovered sk incorrect!")?;
+                let out_path = out_dir.as_ref().map(|out_dir| out_dir.join(&meta.label));
+                secrets.push(report_recovered(format, &meta.label, &meta.label, &s, out_path.as_deref(), show_secrets)?);
+            }
+            json!({ "secrets": secrets })
+        }
+        Command::Sign { user, message, label } => {
+            let pwd_guess = passwords.ask("Password")?;
+            let (sig, pubkey) = rejected(client.sign(&user, &label, message.as_bytes(), &pwd_guess).await, "Signing failed, wrong password!")?;
+            let (signature, public_key) = (hex::encode(sig), hex::encode(pubkey));
+            say!(format, "Signature: {}", signature);
+            say!(format, "Public key: {}", public_key);
+            json!({ "signature": signature, "public_key": public_key })
+        }
+        Command::Decrypt { user, input, out, label } => {
+            let ciphertext = fs::read(&input)?;
+            let pwd_guess = passwords.ask("Password")?;
+            let plaintext = rejected(client.decrypt(&user, &label, &ciphertext, &pwd_guess).await, "Decryption failed, wrong password!")?;
+            match &out {
+                Some(out_path) => {
+                    fs::write(out_path, &*plaintext)?;
+                    say!(format, "Decrypted {} to {}", input.display(), out_path.display());
+                }
+                None if show_secrets => say!(format, "Decrypted: {}", String::from_utf8_lossy(&plaintext)),
+                None => say!(format, "Decrypted {} bytes, pass --show-secrets or --out to see them", plaintext.len()),
+            }
+            json!({
+                "size": plaintext.len(),
+                "written_to": out,
+                "plaintext_hex": (show_secrets && out.is_none()).then(|| hex::encode(&*plaintext)),
+            })
+        }
+        Command::ChangePassword { user } => {
+            let old_pwd = passwords.ask("Old password")?;
+            let new_pwd = passwords.ask_new("New password")?;
+            rejected(client.change_password(&user, &old_pwd, &new_pwd).await, "Old password incorrect!")?;
+            say!(format, "Password changed for user {}", user);
+            json!({ "user": user })
+        }
+        Command::DeleteUser { user } => {
+            let pwd = passwords.ask("Password")?;
+            rejected(client.delete(&user, &pwd).await, "Password incorrect!")?;
+            say!(format, "Deleted user {}", user);
+            json!({ "user": user })
+        }
+        Command::RotateSk { user, label, secret_file } => {
+            let new_sk = read_text(secret_file.as_deref(), "New secret", true)?;
+            let pwd = passwords.ask("Password")?;
+            let version = rejected(client.rotate(&user, &label, &new_sk, &pwd).await, "Password incorrect!")?;
+            say!(format, "Rotated sk for user {} to version {}", user, version);
+            json!({ "user": user, "label": label, "version": version })
+        }
+        Command::AddFactor { user, factor_id, kind, auth_factor } => {
+            let value = match kind {
+                FactorKind::BackupCode => Zeroizing::new(generate_backup_code(&mut ChaCha20Rng::from_entropy())),
+                FactorKind::Password => passwords.ask_new("New factor password")?,
+                FactorKind::SecurityQuestions => read_text(None, "Answers separated by '|'", true)?,
+            };
+            let auth_value = passwords.ask(&format!("Value of factor {}", auth_factor))?;
+            let incorrect = format!("Factor {} incorrect!", auth_factor);
+            rejected(client.add_factor(&user, &factor_id, kind, &value, &auth_factor, &auth_value).await, &incorrect)?;
+            say!(format, "Added factor {} for user {}", factor_id, user);
+            let backup_code = (kind == FactorKind::BackupCode).then(|| (*value).clone());
+            if let Some(code) = &backup_code {
+                say!(format, "Backup code: {}", code);
+            }
+            json!({ "factor_id": factor_id, "kind": kind, "backup_code": backup_code })
+        }
+        Command::RevokeFactor { user, factor_id, auth_factor } => {
+            let auth_value = passwords.ask(&format!("Value of factor {}", auth_factor))?;
+            let incorrect = format!("Factor {} incorrect!", auth_factor);
+            rejected(client.revoke_factor(&user, &factor_id, &auth_factor, &auth_value).await, &incorrect)?;
+            say!(format, "Revoked factor {} for user {}", factor_id, user);
+            json!({ "factor_id": factor_id })
+        }
+        Command::ListFactors { user } => {
+            let factors = client.list_factors(&user).await?;
+            print_factors(format, &factors);
+            json!({ "factors": factors })
+        }
+        Command::RecoverWithFactor { user, factor_id, label, out } => {
+            let value = passwords.ask(&format!("Value of factor {}", factor_id))?;
+            let s = rejected(client.recover_with_factor(&user, &label, &factor_id, &value).await, "Recovered sk incorrect!")?;
+            report_recovered(format, "sk", &label, &s, out.as_deref(), show_secrets)?
+        }
+        Command::SetGuardians { user, threshold, guardians } => {
+            let policy = GuardianPolicy { threshold, guardians };
+            let pwd = passwords.ask("Password")?;
+            rejected(client.set_guardians(&user, &policy, &pwd).await, "Password incorrect!")?;
+            say!(format, "Set {} of {} guardians for user {}", policy.threshold, policy.guardians.len(), user);
+            json!({ "guardians": policy })
+        }
+        Command::ListGuardians { user } => {
+            let guardians = client.list_guardians(&user).await?;
+            print_guardians(format, &user, &guardians);
+            json!({ "guardians": guardians })
+        }
+        Command::RecoverWithGuardians { user, request_id, approvals, label, out } => {
+            let approvals = approvals
                 .iter()
-                .map(|addr| DecExecClient::connect(addr.clone()))
-        )
-        .await
-        .into_iter()
-        .collect::<Result<Vec<_>, _>>()?;
-
-    match &cmd[..] {
-        "seed_prgs" => {
-            seed_prgs(&mut clients).await?;
-        }
-        "upload_sk_and_pwd" => {
-            let id = &args[2];
-            let sk = &args[3];
-            let pwd = &args[4];
-            println!("Uploading sk {}, pwd {} for user {}", sk, pwd, id);
-            upload_sk_and_pwd(&mut clients, id, sk, pwd).await?;
-        }
-        "recover_sk" => {
-            let id = &args[2];
-            let pwd_guess = &args[3];
-
-            println!(
-                "Recovering sk with pwd guess {}, for user {}",
-                pwd_guess, id
-            );
-
-            let s = recover_sk(&mut clients, id, pwd_guess).await?;
-
-            if s.is_empty() {
-                println!("Recovered sk incorrect!");
-            } else {
-                let sk_string = String::from_utf8(s).unwrap();
-                println!("Recovered sk: {}", sk_string);
+                .map(hex::decode)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| invalid_input(format!("bad approval: {}", e)))?;
+            let s = client.recover_with_guardians(&user, &label, &request_id, &approvals).await?;
+            report_recovered(format, "sk", &label, &s, out.as_deref(), show_secrets)?
+        }
+        Command::SetRecoveryDelay { user, seconds } => {
+            let pwd = passwords.ask("Password")?;
+            let effective_at = rejected(client.set_recovery_delay(&user, seconds, &pwd).await, "Password incorrect!")?;
+            say!(format, "Recovery delay for user {} is {} seconds from {}", user, seconds, effective_at);
+            json!({ "delay": seconds, "effective_at": effective_at })
+        }
+        Command::RequestRecovery { user, label } => {
+            let pwd = passwords.ask("Password")?;
+            let pending = rejected(client.request_recovery(&user, &label, &pwd).await, "Password incorrect!")?;
+            say!(format, "Recovery request ID: {}", pending.request_id);
+            say!(format, "Ready at: {}", pending.ready_at);
+            json!(pending)
+        }
+        Command::CompleteRecovery { user, request_id, label, out } => {
+            let pwd_guess = passwords.ask("Password")?;
+            let s = rejected(client.complete_recovery(&user, &label, &request_id, &pwd_guess).await, "Recovered sk incorrect!")?;
+            report_recovered(format, "sk", &label, &s, out.as_deref(), show_secrets)?
+        }
+        Command::CancelRecovery { user, request_id } => {
+            let pwd = passwords.ask("Password")?;
+            rejected(client.cancel_recovery(&user, request_id.as_deref(), &pwd).await, "Password incorrect!")?;
+            say!(format, "Cancelled pending recoveries for user {}", user);
+            json!({ "request_id": request_id })
+        }
+        Command::RecoveryStatus { user } => {
+            let state = client.recovery_status(&user).await?;
+            print_recovery_state(format, &state);
+            json!(state)
+        }
+        Command::Status { user } => {
+            let status = client.status(&user).await?;
+            let UserStatus { secrets, factors, guardians, recovery } = &status;
+            say!(format, "Secrets:");
+            print_secrets(format, secrets);
+            say!(format, "Factors:");
+            print_factors(format, factors);
+            say!(format, "Guardians:");
+            print_guardians(format, &user, guardians);
+            print_recovery_state(format, recovery);
+            json!(status)
+        }
+        Command::CommitteeStatus => {
+            let statuses = client.node_statuses().await?;
+            for (rank, status) in statuses.iter().enumerate() {
+                say!(
+                    format,
+                    "rank {}: epoch {}, threshold {}, members {:?}, {} users",
+                    rank, status.committee.epoch, status.committee.threshold, status.committee.members, status.users.len()
+                );
             }
+            json!({ "nodes": statuses })
         }
+        Command::AuditLog { out_dir } => {
+            let raw_logs = client.audit_logs().await?;
+            let mut logs = Vec::new();
+            let mut nodes = Vec::new();
+            let mut broken = 0;
+            for (rank, raw_log) in raw_logs.iter().enumerate() {
+                if let Some(out_dir) = &out_dir {
+                    fs::write(out_dir.join(format!("audit{}.log", rank)), raw_log)?;
+                }
+                let log = raw_log
+                    .split(|&b| b == b'\n')
+                    .filter(|line| !line.is_empty())
+                    .map(serde_json::from_slice::<AuditEntry>)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(ClientError::from)?;
+                match verify_audit_chain(&log) {
+                    Ok(()) => {
+                        let head = log.last().map_or_else(audit_genesis_hash, |e| e.hash.clone());
+                        say!(format, "rank {}: {} records, chain intact, head {}", rank, log.len(), head);
+                        nodes.push(json!({ "rank": rank, "records": log.len(), "head": head, "broken_at": null }));
+                    }
+                    Err(seq) => {
+                        say!(format, "rank {}: {} records, chain broken at record {}", rank, log.len(), seq);
+                        nodes.push(json!({ "rank": rank, "records": log.len(), "head": null, "broken_at": seq }));
+                        broken += 1;
+                    }
+                }
+                logs.push(log);
+            }
+            let problems = audit_inconsistencies(&logs);
+            for problem in &problems {
+                say!(format, "{}", problem);
+            }
+            let result = json!({ "nodes": nodes, "problems": problems });
+            if broken > 0 || !problems.is_empty() {
+                return Err(Box::new(Failure {
+                    code: "audit",
+                    exit_code: EXIT_AUDIT,
+                    message: format!("Audit failed: {} broken chains, {} inconsistencies", broken, problems.len()),
+                    result,
+                }));
+            }
+            result
+        }
+        Command::Reshare { threshold, members } => {
+            let (new, users) = client.reshare(threshold, members).await?;
+            say!(format, "Moved {} users to epoch {}: threshold {}, members {:?}", users, new.epoch, new.threshold, new.members);
+            json!({ "committee": new, "users": users })
+        }
+        Command::Encrypt { .. } | Command::GuardianKeygen | Command::GuardianRequest { .. } | Command::GuardianApprove { .. } => {
+            unreachable!("handled without nodes")
+        }
+    })
+}
+
+// What --output json prints: one document per run, on success or failure.
+#[derive(Serialize)]
+struct Report {
+    version: u32,
+    command: String,
+    // "ok" or "error"
+    status: &'static str,
+    exit_code: i32,
+    elapsed_ms: f64,
+    result: Value,
+    error: Option<ErrorReport>,
+    // every request sent to the nodes, in the order they finished
+    requests: Vec<RequestReport>,
+}
 
-        _ => println!("Missing/wrong arguments"),
+#[derive(Serialize)]
+struct ErrorReport {
+    code: &'static str,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct RequestReport {
+    function: String,
+    elapsed_ms: f64,
+    // why the nodes could not be reached, in which case there are no results
+    error: Option<String>,
+    nodes: Vec<NodeReport>,
+}
+
+#[derive(Serialize)]
+struct NodeReport {
+    rank: usize,
+    // "ok" or "error"
+    status: &'static str,
+    error: Option<ErrorReport>,
+}
+
+fn millis(elapsed: Duration) -> f64 {
+    elapsed.as_secs_f64() * 1000.0
+}
+
+fn report(command: String, elapsed: Duration, res: &Result<Value, Box<dyn Error>>, calls: &[Call]) -> Report {
+    let (result, error) = match res {
+        Ok(result) => (result.clone(), None),
+        Err(e) => (
+            e.downcast_ref::<Failure>().map_or(Value::Null, |failure| failure.result.clone()),
+            Some(ErrorReport { code: classify(e.as_ref()).0, message: e.to_string() }),
+        ),
     };
-    Ok(())
+    let requests = calls
+        .iter()
+        .map(|call| RequestReport {
+            function: call.func_name.clone(),
+            elapsed_ms: millis(call.elapsed),
+            error: call.nodes.as_ref().err().cloned(),
+            nodes: call.nodes.as_deref().unwrap_or_default()
+                .iter()
+                .map(|(rank, error)| NodeReport {
+                    rank: *rank,
+                    status: if error.is_some() { "error" } else { "ok" },
+                    error: error.as_ref().map(|e| ErrorReport { code: e.code(), message: e.to_string() }),
+                })
+                .collect(),
+        })
+        .collect();
+    Report {
+        version: REPORT_VERSION,
+        command,
+        status: if error.is_some() { "error" } else { "ok" },
+        exit_code: res.as_ref().map_or_else(|e| classify(e.as_ref()).1, |_| 0),
+        elapsed_ms: millis(elapsed),
+        result,
+        error,
+        requests,
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    init_tracing("warn");
+    let matches = Cli::command().get_matches();
+    let command = matches.subcommand_name().unwrap_or_default().to_owned();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let format = cli.output;
+
+    let start = Instant::now();
+    let mut calls = Vec::new();
+    let res = run(cli, &mut calls).await;
+    let report = report(command, start.elapsed(), &res, &calls);
+    match format {
+        Format::Json => println!("{}", serde_json::to_string(&report).expect("reports serialize")),
+        Format::Text => match &res {
+            Ok(_) => {}
+            Err(e) if e.is::<Failure>() => println!("{}", e),
+            // errors from the nodes read best as one line per request, not as Debug
+            Err(e) => eprintln!("Error: {}", e),
+        },
+    }
+    std::process::exit(report.exit_code);
 }
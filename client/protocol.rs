@@ -0,0 +1,745 @@
+// Copyright 2023 The Dots Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The requests the client makes of the nodes and how it combines their
+// answers. Wrong passwords are not errors here: functions report them in
+// their result, as the nodes do.
+
+use std::iter;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use elliptic_curve::Field;
+use p256::Scalar;
+use rand::prelude::*;
+use rand_chacha::ChaCha20Rng;
+use serde::de::DeserializeOwned;
+use tracing::{debug, info};
+use uuid::Uuid;
+use vsss_rs::Share;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::transport::Transport;
+use crate::util::*;
+use crate::ClientError;
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+fn all_ranks(transport: &impl Transport) -> Vec<usize> {
+    (0..transport.num_nodes()).collect()
+}
+
+// The same arguments for each of the nodes at `ranks`.
+fn same_args(ranks: &[usize], args: Vec<Vec<u8>>) -> Vec<(usize, Vec<Vec<u8>>)> {
+    ranks.iter().map(|&rank| (rank, args.clone())).collect()
+}
+
+// The outputs of the nodes at `ranks`, in that order. Nodes that failed sent
+// an error instead; if any did, all of their errors are returned.
+fn node_outputs(outputs: Vec<Vec<u8>>, ranks: &[usize]) -> Result<Vec<Vec<u8>>, NodeErrors> {
+    let errors: Vec<(usize, ServerError)> = iter::zip(ranks, &outputs)
+        .filter_map(|(&rank, output)| ServerError::from_output(output).map(|e| (rank, e)))
+        .collect();
+    if !errors.is_empty() {
+        return Err(NodeErrors(errors));
+    }
+    Ok(outputs)
+}
+
+fn parse_outputs<T: DeserializeOwned>(res: Vec<Vec<u8>>, ranks: &[usize]) -> Result<Vec<T>, ClientError> {
+    Ok(node_outputs(res, ranks)?
+        .iter()
+        .map(|output| serde_json::from_slice(output))
+        .collect::<Result<Vec<T>, _>>()?)
+}
+
+pub(crate) async fn seed_prgs(transport: &impl Transport) -> Result<(), ClientError> {
+    let ranks = all_ranks(transport);
+    let res = transport.exec(Uuid::now_v7(), "seed_prgs", same_args(&ranks, vec![])).await?;
+    node_outputs(res, &ranks)?;
+
+    Ok(())
+}
+
+// Returns what every node reports about the committee, indexed by rank.
+pub(crate) async fn committee_statuses(transport: &impl Transport) -> Result<Vec<CommitteeStatus>, ClientError> {
+    let ranks = all_ranks(transport);
+    let res = transport.exec(Uuid::now_v7(), "committee_status", same_args(&ranks, vec![])).await?;
+
+    parse_outputs(res, &ranks)
+}
+
+// The audit log of every node, in rank order, as raw JSON lines.
+pub(crate) async fn fetch_audit_logs(transport: &impl Transport) -> Result<Vec<Vec<u8>>, ClientError> {
+    let ranks = all_ranks(transport);
+    let res = transport.exec(Uuid::now_v7(), "audit_log", same_args(&ranks, vec![])).await?;
+
+    Ok(node_outputs(res, &ranks)?)
+}
+
+pub(crate) async fn fetch_committee(transport: &impl Transport) -> Result<Committee, ClientError> {
+    let statuses = committee_statuses(transport).await?;
+    let committee = statuses[0].committee.clone();
+    if statuses.iter().any(|status| status.committee != committee) {
+        return Err(ClientError::Disagreement("nodes disagree on the current committee".to_owned()));
+    }
+    // nodes would reject the arguments of a different schema version anyway,
+    // but only after the client has done its part
+    if let Some((rank, status)) = statuses.iter().enumerate().find(|(_, status)| status.schema_version != REQUEST_SCHEMA_VERSION) {
+        return Err(ClientError::Incompatible(format!("node {} checks requests against schema version {}, this client sends version {}", rank, status.schema_version, REQUEST_SCHEMA_VERSION)));
+    }
+    debug!(epoch = committee.epoch, threshold = committee.threshold, members = ?committee.members, "fetched committee");
+    Ok(committee)
+}
+
+// Splits the secret key for the committee. Returns the serialized shares for
+// each member in member order, along with the salt and salted hash.
+fn share_sk(id: &str, sk: &str, committee: &Committee, rng: &mut ChaCha20Rng) -> (Vec<Vec<u8>>, [u8; 32], Vec<u8>) {
+    let field_elts = sk_to_field_elts(sk);
+    let mut shares_vec: Vec<Vec<Share<33>>> = Vec::new();
+    for _ in 0..committee.size() {
+        shares_vec.push(Vec::new());
+    }
+
+    for nzs in field_elts.as_slice() {
+        // 32 for field size, 1 for identifier = 33
+        let res = split_secret(*nzs.as_ref(), committee.threshold, committee.size(), rng);
+        for i in 0..committee.size() {
+            shares_vec[i].push(res[i]);
+        }
+    }
+    let sk_shares: Vec<Vec<u8>> = shares_vec
+        .iter()
+        .map(|share| serde_json::to_vec(share).unwrap())
+        .collect();
+    shares_vec.iter_mut().flatten().for_each(|share| share.0.zeroize());
+
+    // Every node stores the salted hash next to its share, so that on recovery
+    // the client can check the key it combined: a wrong password guess
+    // combines to some other key.
+    let salt = rng.gen::<[u8; 32]>();
+    let sk_scalars: Zeroizing<Vec<Scalar>> = Zeroizing::new(field_elts.iter().map(|nzs| *nzs.as_ref()).collect());
+    let hash = compute_sk_hash(id, &salt, &sk_scalars);
+
+    (sk_shares, salt, hash)
+}
+
+fn share_pwd(pwd: &str, committee: &Committee, rng: &mut ChaCha20Rng) -> Vec<Vec<u8>> {
+    let pwd_nzs = Zeroizing::new(string_hash_to_nzs(pwd));
+    let mut pwd_shares = split_secret(*pwd_nzs.as_ref(), committee.threshold, committee.size(), rng);
+    let serialized = pwd_shares.iter().map(|share| share.as_ref().to_vec()).collect();
+    pwd_shares.iter_mut().for_each(|share| share.0.zeroize());
+    serialized
+}
+
+/// What a user can store.
+pub enum SecretInput {
+    Text(String),
+    // 64 hex digits
+    EcKey(String),
+    // stored in hybrid mode
+    Bytes(Vec<u8>),
+}
+
+impl Drop for SecretInput {
+    fn drop(&mut self) {
+        match self {
+            SecretInput::Text(sk) | SecretInput::EcKey(sk) => sk.zeroize(),
+            SecretInput::Bytes(secret) => secret.zeroize(),
+        }
+    }
+}
+
+// A secret split for the committee: the serialized shares for each member in
+// member order, and the verification data every member stores with them.
+struct PreparedSecret {
+    meta: SecretMeta,
+    sk_shares: Vec<Vec<u8>>,
+    verification: Vec<Vec<u8>>,
+}
+
+fn prepare_secret(id: &str, label: &str, input: &SecretInput, committee: &Committee, rng: &mut ChaCha20Rng) -> Result<PreparedSecret, ClientError> {
+    validate_label(label).map_err(ClientError::InvalidInput)?;
+    let (kind, size, sk_shares, verification) = match input {
+        SecretInput::Text(sk) => {
+            let (sk_shares, salt, hash) = share_sk(id, sk, committee, rng);
+            (SecretKind::Text, sk.len(), sk_shares, vec![salt.to_vec(), hash])
+        }
+        // Nodes keep the public key rather than a salted hash, so a recovered
+        // key can be checked against it.
+        SecretInput::EcKey(hex_key) => {
+            let sk = Zeroizing::new(ec_key_from_hex(hex_key).map_err(ClientError::InvalidInput)?);
+            let sk_shares = split_secret(*sk.as_ref(), committee.threshold, committee.size(), rng)
+                .into_iter()
+                .map(|share| serde_json::to_vec(&vec![share]).unwrap())
+                .collect();
+            (SecretKind::EcKey, 32, sk_shares, vec![ec_public_key(sk.as_ref())])
+        }
+        // Hybrid mode for large secrets: the secret is encrypted under a random
+        // key, only the key goes through the password-gated sharing, and every
+        // member stores the whole ciphertext.
+        SecretInput::Bytes(secret) => {
+            let key = Zeroizing::new(Scalar::random(&mut *rng));
+            let ciphertext = encrypt_secret(id, &key, secret, rng);
+            let key_shares = split_secret(*key, committee.threshold, committee.size(), rng)
+                .into_iter()
+                .map(|share| serde_json::to_vec(&vec![share]).unwrap())
+                .collect();
+            (SecretKind::Encrypted, secret.len(), key_shares, vec![ciphertext])
+        }
+    };
+    let meta = SecretMeta {
+        label: label.to_owned(),
+        kind,
+        created: now_secs(),
+        size,
+    };
+    Ok(PreparedSecret { meta, sk_shares, verification })
+}
+
+// Registers the user with a single secret, replacing the password and any
+// secrets they had before.
+pub(crate) async fn upload_secret(transport: &impl Transport, id: &str, label: &str, input: &SecretInput, pwd: &str) -> Result<(), ClientError> {
+    let committee = fetch_committee(transport).await?;
+    let rng = &mut ChaCha20Rng::from_entropy();
+    let secret = prepare_secret(id, label, input, &committee, rng)?;
+    let pwd_shares = share_pwd(pwd, &committee, rng);
+    let func_name = match secret.meta.kind {
+        SecretKind::Text => "upload_sk_and_pwd",
+        SecretKind::EcKey => "upload_ec_key",
+        SecretKind::Encrypted => "upload_encrypted",
+    };
+    let meta = serde_json::to_vec(&secret.meta)?;
+
+    let node_args = iter::zip(&committee.members, iter::zip(secret.sk_shares, pwd_shares))
+        .map(|(&rank, (sk_share, pwd_share))| {
+            let mut args = vec![id.as_bytes().to_owned(), sk_share, pwd_share];
+            args.extend(secret.verification.iter().cloned());
+            args.push(meta.clone());
+            (rank, args)
+        })
+        .collect();
+    let res = transport.exec(Uuid::now_v7(), func_name, node_args).await?;
+    node_outputs(res, &committee.members)?;
+
+    Ok(())
+}
+
+// Adds another labeled secret under the user's current password. Returns
+// false, storing nothing, if the password was wrong.
+pub(crate) async fn add_secret(transport: &impl Transport, id: &str, label: &str, input: &SecretInput, pwd: &str) -> Result<bool, ClientError> {
+    let committee = fetch_committee(transport).await?;
+    let rng = &mut ChaCha20Rng::from_entropy();
+    let secret = prepare_secret(id, label, input, &committee, rng)?;
+    let pwd_guess_shares = compute_pwd_guess(DEFAULT_FACTOR, pwd, &committee);
+    let meta = serde_json::to_vec(&secret.meta)?;
+
+    let node_args = iter::zip(&committee.members, iter::zip(pwd_guess_shares, secret.sk_shares))
+        .map(|(&rank, (pwd_guess_share, sk_share))| {
+            let mut args = vec![id.as_bytes().to_owned(), pwd_guess_share, meta.clone(), sk_share];
+            args.extend(secret.verification.iter().cloned());
+            (rank, args)
+        })
+        .collect();
+    let res = transport.exec(Uuid::now_v7(), "add_secret", node_args).await?;
+
+    let added: Vec<bool> = parse_outputs(res, &committee.members)?;
+    if added.iter().any(|&a| a != added[0]) {
+        return Err(ClientError::Disagreement("nodes disagree on whether the secret was added".to_owned()));
+    }
+    Ok(added[0])
+}
+
+// The user's secrets, as every committee member reports them.
+pub(crate) async fn list_secrets(transport: &impl Transport, id: &str) -> Result<Vec<SecretMeta>, ClientError> {
+    let committee = fetch_committee(transport).await?;
+
+    let res = transport.exec(Uuid::now_v7(), "list_secrets", same_args(&committee.members, vec![id.as_bytes().to_owned()])).await?;
+
+    let indexes: Vec<Vec<SecretMeta>> = parse_outputs(res, &committee.members)?;
+    if indexes.iter().any(|index| *index != indexes[0]) {
+        return Err(ClientError::Disagreement("nodes disagree on the user's secrets".to_owned()));
+    }
+    Ok(indexes[0].clone())
+}
+
+// Shares of the hashed guess for one of the user's factors, each followed by
+// the factor ID unless it is the password. The guess must already be
+// normalized for the factor's kind.
+fn compute_pwd_guess(factor: &str, pwd_guess: &str, committee: &Committee) -> Vec<Vec<u8>> {
+    let rng = &mut ChaCha20Rng::from_entropy();
+    let pwd_guess_nzs = Zeroizing::new(string_hash_to_nzs(pwd_guess));
+    let mut pwd_guess_shares = split_secret(*pwd_guess_nzs.as_ref(), committee.threshold, committee.size(), rng);
+    let args = pwd_guess_shares
+        .iter()
+        .map(|x| {
+            let mut arg = x.as_ref().to_vec();
+            if factor != DEFAULT_FACTOR {
+                arg.extend_from_slice(factor.as_bytes());
+            }
+            arg
+        })
+        .collect();
+    pwd_guess_shares.iter_mut().for_each(|share| share.0.zeroize());
+    args
+}
+
+// Returns the recovered sk, or an empty vector if it does not match the stored
+// hash, public key or ciphertext (i.e. the password guess was wrong). EC keys
+// are returned hex encoded, hybrid mode secrets as the decrypted bytes.
+fn aggregate_sk(id: &str, outputs: &[&[u8]], committee: &Committee) -> Result<Secret<Vec<u8>>, ClientError> {
+    let deserialized = outputs
+        .iter()
+        .map(|x| RecoveryOutput::from_bytes(x).map_err(ClientError::Malformed))
+        .collect::<Result<Vec<_>, _>>()?;
+    let sk_shares: Vec<&[Vec<u8>]> = deserialized.iter().map(|x| x.shares.as_slice()).collect();
+    // every node holds one share per chunk of the key
+    let num_chunks = sk_shares.first().map_or(0, |shares| shares.len());
+    if num_chunks == 0 {
+        return Err(ClientError::Malformed("nodes returned no key shares".to_owned()));
+    }
+    if sk_shares.iter().any(|shares| shares.len() != num_chunks) {
+        return Err(ClientError::Malformed("nodes returned different numbers of key shares".to_owned()));
+    }
+    // get back (2t, n) shares bc of multiplication
+    let recover_threshold = committee.threshold * 2;
+    let mut sk_scalars = Zeroizing::new(Vec::with_capacity(num_chunks));
+    for i in 0..num_chunks {
+        let scalars = sk_shares
+            .iter()
+            .map(|shares| Share::try_from(shares[i].as_slice()))
+            .collect::<Result<Vec<Share<33>>, _>>();
+        let mut scalars = scalars.map_err(|_| ClientError::Malformed("key share is not a share".to_owned()))?;
+        let res = combine_shares(&scalars, recover_threshold);
+        scalars.iter_mut().for_each(|share| share.0.zeroize());
+        let sk_scalar = res.ok_or_else(|| ClientError::Malformed("could not combine key shares".to_owned()))?;
+        sk_scalars.push(sk_scalar);
+    }
+
+    if deserialized.iter().any(|x| x.ciphertext.is_some()) {
+        let ciphertexts: Vec<&[u8]> = deserialized.iter().map(|x| x.ciphertext.as_deref().unwrap_or_default()).collect();
+        return match decrypt_secret(id, &ciphertexts, sk_scalars.as_slice()) {
+            (SkCheck::Valid, secret) => Ok(secret),
+            (SkCheck::Invalid, _) => Ok(Secret::default()),
+            (SkCheck::Inconsistent(positions), _) => Err(inconsistent_nodes(&positions, committee)),
+        };
+    }
+
+    let is_ec_key = deserialized.iter().any(|x| x.pubkey.is_some());
+    let check = if is_ec_key {
+        let pubkeys: Vec<&[u8]> = deserialized.iter().map(|x| x.pubkey.as_deref().unwrap_or_default()).collect();
+        verify_public_key(&pubkeys, sk_scalars.as_slice())
+    } else {
+        let salts: Vec<&[u8]> = deserialized.iter().map(|x| x.salt.as_slice()).collect();
+        let hashes: Vec<&[u8]> = deserialized.iter().map(|x| x.skhash.as_slice()).collect();
+        verify_sk_hash(id, &salts, &hashes, sk_scalars.as_slice())
+    };
+
+    match check {
+        SkCheck::Valid if is_ec_key => {
+            let mut sk_bytes = sk_scalars[0].to_bytes();
+            let sk_hex = hex::encode(sk_bytes).into_bytes();
+            sk_bytes.as_mut_slice().zeroize();
+            Ok(sk_hex.into())
+        }
+        SkCheck::Valid => Ok(field_elts_to_string(sk_scalars.as_slice()).as_bytes().to_vec().into()),
+        SkCheck::Invalid => Ok(Secret::default()),
+        SkCheck::Inconsistent(positions) => Err(inconsistent_nodes(&positions, committee)),
+    }
+}
+
+fn inconsistent_nodes(positions: &[usize], committee: &Committee) -> ClientError {
+    ClientError::Inconsistent(positions.iter().map(|&i| committee.members[i]).collect())
+}
+
+// Users with a recovery delay must pass the ID of a pending recovery that is
+// ready.
+pub(crate) async fn recover_sk(transport: &impl Transport, id: &str, label: &str, factor: &str, pwd_guess: &str, recovery_request: Option<&str>) -> Result<Secret<Vec<u8>>, ClientError> {
+    let committee = fetch_committee(transport).await?;
+    let pwd_guess_shares = compute_pwd_guess(factor, pwd_guess, &committee);
+
+    let request_id = Uuid::now_v7();
+    info!(%request_id, user = id, label, factor, "recovering");
+    let node_args = iter::zip(&committee.members, pwd_guess_shares)
+        .map(|(&rank, pwd_guess_share)| {
+            let mut args = vec![id.as_bytes().to_owned(), pwd_guess_share, label.as_bytes().to_owned()];
+            args.extend(recovery_request.map(|r| r.as_bytes().to_owned()));
+            (rank, args)
+        })
+        .collect();
+    let res = transport.exec(request_id, "skrecovery", node_args).await?;
+    let res = Zeroizing::new(node_outputs(res, &committee.members)?);
+    let outputs: Vec<&[u8]> = res.iter().map(|output| output.as_slice()).collect();
+
+    let s = aggregate_sk(id, &outputs, &committee)?;
+
+    Ok(s)
+}
+
+// Signs a message with a registered EC key without reconstructing it. Returns
+// the Schnorr signature and the public key it verifies under, or None if the
+// password guess was wrong.
+pub(crate) async fn sign(transport: &impl Transport, id: &str, label: &str, msg: &[u8], pwd_guess: &str) -> Result<Option<(Vec<u8>, Vec<u8>)>, ClientError> {
+    let committee = fetch_committee(transport).await?;
+    let pwd_guess_shares = compute_pwd_guess(DEFAULT_FACTOR, pwd_guess, &committee);
+
+    let node_args = iter::zip(&committee.members, pwd_guess_shares)
+        .map(|(&rank, pwd_guess_share)| (rank, vec![id.as_bytes().to_owned(), pwd_guess_share, msg.to_vec(), label.as_bytes().to_owned()]))
+        .collect();
+    let res = transport.exec(Uuid::now_v7(), "sign", node_args).await?;
+
+    let sig_shares: Vec<SignatureShare> = parse_outputs(res, &committee.members)?;
+    let disagreeing: Vec<usize> = sig_shares
+        .iter()
+        .enumerate()
+        .filter(|(_, x)| x.nonce != sig_shares[0].nonce || x.pubkey != sig_shares[0].pubkey)
+        .map(|(i, _)| i)
+        .collect();
+    if !disagreeing.is_empty() {
+        return Err(inconsistent_nodes(&disagreeing, &committee));
+    }
+
+    let shares = sig_shares
+        .iter()
+        .map(|x| Share::try_from(x.share.as_slice()))
+        .collect::<Result<Vec<Share<33>>, _>>()
+        .map_err(|_| ClientError::Malformed("signature share is not a share".to_owned()))?;
+    // the response hides behind (pwd - guess) * R, so 2t shares again
+    let response = combine_shares(&shares, committee.threshold * 2).ok_or_else(|| ClientError::Malformed("could not combine signature shares".to_owned()))?;
+    let mut sig = sig_shares[0].nonce.clone();
+    sig.extend_from_slice(&response.to_bytes());
+
+    let pubkey = sig_shares[0].pubkey.clone();
+    if verify_schnorr(&pubkey, msg, &sig) {
+        Ok(Some((sig, pubkey)))
+    } else {
+        Ok(None)
+    }
+}
+
+// Decrypts an ECIES ciphertext addressed to a registered EC key. Only the
+// ephemeral key is sent to the servers. Returns None if the password guess was
+// wrong.
+pub(crate) async fn decrypt(transport: &impl Transport, id: &str, label: &str, ciphertext: &[u8], pwd_guess: &str) -> Result<Option<Secret<Vec<u8>>>, ClientError> {
+    let committee = fetch_committee(transport).await?;
+    let ephemeral = ecies_ephemeral(ciphertext).map_err(ClientError::InvalidInput)?;
+    let pwd_guess_shares = compute_pwd_guess(DEFAULT_FACTOR, pwd_guess, &committee);
+
+    let node_args = iter::zip(&committee.members, pwd_guess_shares)
+        .map(|(&rank, pwd_guess_share)| (rank, vec![id.as_bytes().to_owned(), pwd_guess_share, ephemeral.to_vec(), label.as_bytes().to_owned()]))
+        .collect();
+    let res = transport.exec(Uuid::now_v7(), "decrypt", node_args).await?;
+
+    let shares = parse_outputs::<DecryptionShare>(res, &committee.members)?
+        .into_iter()
+        .map(|share| Ok((share.id, decode_point(&share.point)?)))
+        .collect::<Result<Vec<_>, String>>()
+        .map_err(ClientError::Malformed)?;
+    let shared = combine_points(&shares, committee.threshold * 2).ok_or_else(|| ClientError::Malformed("could not combine decryption shares".to_owned()))?;
+    ecies_decrypt(ciphertext, &shared).map_err(ClientError::InvalidInput)
+}
+
+// Replaces the password after the committee has checked the old one. Returns
+// false, leaving the password unchanged, if the old password was wrong.
+pub(crate) async fn change_password(transport: &impl Transport, id: &str, old_pwd: &str, new_pwd: &str) -> Result<bool, ClientError> {
+    let committee = fetch_committee(transport).await?;
+    let pwd_guess_shares = compute_pwd_guess(DEFAULT_FACTOR, old_pwd, &committee);
+    let rng = &mut ChaCha20Rng::from_entropy();
+    let new_pwd_shares: Vec<Vec<u8>> = split_secret(*string_hash_to_nzs(new_pwd).as_ref(), committee.threshold, committee.size(), rng)
+        .iter()
+        .map(|share| share.as_ref().to_vec())
+        .collect();
+
+    let node_args = iter::zip(&committee.members, iter::zip(pwd_guess_shares, new_pwd_shares))
+        .map(|(&rank, (pwd_guess_share, new_pwd_share))| (rank, vec![id.as_bytes().to_owned(), pwd_guess_share, new_pwd_share]))
+        .collect();
+    let res = transport.exec(Uuid::now_v7(), "change_password", node_args).await?;
+
+    let changed: Vec<bool> = parse_outputs(res, &committee.members)?;
+    if changed.iter().any(|&c| c != changed[0]) {
+        return Err(ClientError::Disagreement("nodes disagree on whether the password was changed".to_owned()));
+    }
+    Ok(changed[0])
+}
+
+// Removes the user and everything they stored after the committee has checked
+// the password. Returns false, removing nothing, if it was wrong.
+pub(crate) async fn delete_user(transport: &impl Transport, id: &str, pwd: &str) -> Result<bool, ClientError> {
+    let committee = fetch_committee(transport).await?;
+    let pwd_guess_shares = compute_pwd_guess(DEFAULT_FACTOR, pwd, &committee);
+
+    let node_args = iter::zip(&committee.members, pwd_guess_shares)
+        .map(|(&rank, pwd_guess_share)| (rank, vec![id.as_bytes().to_owned(), pwd_guess_share]))
+        .collect();
+    let res = transport.exec(Uuid::now_v7(), "delete_user", node_args).await?;
+
+    let deleted: Vec<bool> = parse_outputs(res, &committee.members)?;
+    if deleted.iter().any(|&d| d != deleted[0]) {
+        return Err(ClientError::Disagreement("nodes disagree on whether the user was deleted".to_owned()));
+    }
+    Ok(deleted[0])
+}
+
+// Adds a recovery factor after the committee has checked an existing one.
+// Returns false, storing nothing, if the existing factor was wrong.
+pub(crate) async fn add_factor(transport: &impl Transport, id: &str, factor_id: &str, kind: FactorKind, value: &str, auth_factor: &str, auth_value: &str) -> Result<bool, ClientError> {
+    validate_label(factor_id).map_err(ClientError::InvalidInput)?;
+    let committee = fetch_committee(transport).await?;
+    let pwd_guess_shares = compute_pwd_guess(auth_factor, auth_value, &committee);
+    let rng = &mut ChaCha20Rng::from_entropy();
+    let factor_shares = share_pwd(&normalize_factor(kind, value), &committee, rng);
+    let meta = serde_json::to_vec(&FactorMeta {
+        id: factor_id.to_owned(),
+        kind,
+        created: now_secs(),
+    })?;
+
+    let node_args = iter::zip(&committee.members, iter::zip(pwd_guess_shares, factor_shares))
+        .map(|(&rank, (pwd_guess_share, factor_share))| (rank, vec![id.as_bytes().to_owned(), pwd_guess_share, meta.clone(), factor_share]))
+        .collect();
+    let res = transport.exec(Uuid::now_v7(), "add_factor", node_args).await?;
+
+    let added: Vec<bool> = parse_outputs(res, &committee.members)?;
+    if added.iter().any(|&a| a != added[0]) {
+        return Err(ClientError::Disagreement("nodes disagree on whether the factor was added".to_owned()));
+    }
+    Ok(added[0])
+}
+
+// Removes a recovery factor after the committee has checked another (or the
+// same) one. Returns false, leaving the factors unchanged, if it was wrong.
+pub(crate) async fn revoke_factor(transport: &impl Transport, id: &str, factor_id: &str, auth_factor: &str, auth_value: &str) -> Result<bool, ClientError> {
+    let committee = fetch_committee(transport).await?;
+    let pwd_guess_shares = compute_pwd_guess(auth_factor, auth_value, &committee);
+
+    let node_args = iter::zip(&committee.members, pwd_guess_shares)
+        .map(|(&rank, pwd_guess_share)| (rank, vec![id.as_bytes().to_owned(), pwd_guess_share, factor_id.as_bytes().to_owned()]))
+        .collect();
+    let res = transport.exec(Uuid::now_v7(), "revoke_factor", node_args).await?;
+
+    let revoked: Vec<bool> = parse_outputs(res, &committee.members)?;
+    if revoked.iter().any(|&r| r != revoked[0]) {
+        return Err(ClientError::Disagreement("nodes disagree on whether the factor was revoked".to_owned()));
+    }
+    Ok(revoked[0])
+}
+
+// The user's recovery factors, as every committee member reports them.
+pub(crate) async fn list_factors(transport: &impl Transport, id: &str) -> Result<Vec<FactorMeta>, ClientError> {
+    let committee = fetch_committee(transport).await?;
+
+    let res = transport.exec(Uuid::now_v7(), "list_factors", same_args(&committee.members, vec![id.as_bytes().to_owned()])).await?;
+
+    let factors: Vec<Vec<FactorMeta>> = parse_outputs(res, &committee.members)?;
+    if factors.iter().any(|f| *f != factors[0]) {
+        return Err(ClientError::Disagreement("nodes disagree on the user's recovery factors".to_owned()));
+    }
+    Ok(factors[0].clone())
+}
+
+// Normalizes a guess for one of the user's factors according to its kind.
+pub(crate) async fn factor_guess(transport: &impl Transport, id: &str, factor_id: &str, value: &str) -> Result<String, ClientError> {
+    let factor = list_factors(transport, id)
+        .await?
+        .into_iter()
+        .find(|factor| factor.id == factor_id)
+        .ok_or_else(|| ClientError::InvalidInput(format!("user has no recovery factor {}", factor_id)))?;
+    Ok(normalize_factor(factor.kind, value))
+}
+
+// Designates the guardians who can approve a recovery instead of the
+// password. Returns false, changing nothing, if the password was wrong.
+pub(crate) async fn set_guardians(transport: &impl Transport, id: &str, policy: &GuardianPolicy, pwd: &str) -> Result<bool, ClientError> {
+    policy.validate().map_err(ClientError::InvalidInput)?;
+    let committee = fetch_committee(transport).await?;
+    let pwd_guess_shares = compute_pwd_guess(DEFAULT_FACTOR, pwd, &committee);
+    let policy = serde_json::to_vec(policy)?;
+
+    let node_args = iter::zip(&committee.members, pwd_guess_shares)
+        .map(|(&rank, pwd_guess_share)| (rank, vec![id.as_bytes().to_owned(), pwd_guess_share, policy.clone()]))
+        .collect();
+    let res = transport.exec(Uuid::now_v7(), "set_guardians", node_args).await?;
+
+    let set: Vec<bool> = parse_outputs(res, &committee.members)?;
+    if set.iter().any(|&s| s != set[0]) {
+        return Err(ClientError::Disagreement("nodes disagree on whether the guardians were set".to_owned()));
+    }
+    Ok(set[0])
+}
+
+// The user's guardians, as every committee member reports them.
+pub(crate) async fn list_guardians(transport: &impl Transport, id: &str) -> Result<Option<GuardianPolicy>, ClientError> {
+    let committee = fetch_committee(transport).await?;
+
+    let res = transport.exec(Uuid::now_v7(), "list_guardians", same_args(&committee.members, vec![id.as_bytes().to_owned()])).await?;
+
+    let policies: Vec<Option<GuardianPolicy>> = parse_outputs(res, &committee.members)?;
+    if policies.iter().any(|p| *p != policies[0]) {
+        return Err(ClientError::Disagreement("nodes disagree on the user's guardians".to_owned()));
+    }
+    Ok(policies[0].clone())
+}
+
+// Recovers a secret with guardian signatures over the approval message for
+// recovery_request_id instead of a password. Each request ID works once.
+pub(crate) async fn recover_with_guardians(transport: &impl Transport, id: &str, label: &str, recovery_request_id: &str, approvals: &[Vec<u8>]) -> Result<Secret<Vec<u8>>, ClientError> {
+    let committee = fetch_committee(transport).await?;
+    let approvals = serde_json::to_vec(approvals)?;
+
+    let res = transport.exec(Uuid::now_v7(), "guardian_recovery", same_args(&committee.members, vec![id.as_bytes().to_owned(), recovery_request_id.as_bytes().to_owned(), approvals, label.as_bytes().to_owned()])).await?;
+    let res = Zeroizing::new(node_outputs(res, &committee.members)?);
+    let outputs: Vec<&[u8]> = res.iter().map(|output| output.as_slice()).collect();
+
+    aggregate_sk(id, &outputs, &committee)
+}
+
+// Sets how long recoveries wait between the password check and the release
+// of shares. Returns when the new delay takes effect, which is later than now
+// if it is shorter than the current one, or None if the password was wrong.
+pub(crate) async fn set_recovery_delay(transport: &impl Transport, id: &str, seconds: u64, pwd: &str) -> Result<Option<u64>, ClientError> {
+    let committee = fetch_committee(transport).await?;
+    let pwd_guess_shares = compute_pwd_guess(DEFAULT_FACTOR, pwd, &committee);
+
+    let node_args = iter::zip(&committee.members, pwd_guess_shares)
+        .map(|(&rank, pwd_guess_share)| (rank, vec![id.as_bytes().to_owned(), pwd_guess_share, seconds.to_string().into_bytes()]))
+        .collect();
+    let res = transport.exec(Uuid::now_v7(), "set_recovery_delay", node_args).await?;
+
+    // nodes read their own clocks, so only agreement on success is required
+    let effective: Vec<Option<u64>> = parse_outputs(res, &committee.members)?;
+    if effective.iter().any(|e| e.is_some() != effective[0].is_some()) {
+        return Err(ClientError::Disagreement("nodes disagree on whether the delay was set".to_owned()));
+    }
+    Ok(effective.into_iter().flatten().max())
+}
+
+// Opens a pending recovery after the committee has checked the password.
+// Returns None if the password was wrong.
+pub(crate) async fn request_recovery(transport: &impl Transport, id: &str, label: &str, pwd: &str) -> Result<Option<PendingRecovery>, ClientError> {
+    let committee = fetch_committee(transport).await?;
+    let pwd_guess_shares = compute_pwd_guess(DEFAULT_FACTOR, pwd, &committee);
+    let recovery_request = Uuid::now_v7().to_string();
+
+    let node_args = iter::zip(&committee.members, pwd_guess_shares)
+        .map(|(&rank, pwd_guess_share)| (rank, vec![id.as_bytes().to_owned(), pwd_guess_share, recovery_request.as_bytes().to_owned(), label.as_bytes().to_owned()]))
+        .collect();
+    let res = transport.exec(Uuid::now_v7(), "request_recovery", node_args).await?;
+
+    let opened: Vec<Option<PendingRecovery>> = parse_outputs(res, &committee.members)?;
+    if opened.iter().any(|o| o.is_some() != opened[0].is_some()) {
+        return Err(ClientError::Disagreement("nodes disagree on whether the recovery was requested".to_owned()));
+    }
+    // the latest ready time is when every node releases its shares
+    Ok(opened.into_iter().flatten().max_by_key(|pending| pending.ready_at))
+}
+
+// Cancels one pending recovery, or all of them and any pending lowering of
+// the delay. Returns false, cancelling nothing, if the password was wrong.
+pub(crate) async fn cancel_recovery(transport: &impl Transport, id: &str, recovery_request: Option<&str>, pwd: &str) -> Result<bool, ClientError> {
+    let committee = fetch_committee(transport).await?;
+    let pwd_guess_shares = compute_pwd_guess(DEFAULT_FACTOR, pwd, &committee);
+
+    let node_args = iter::zip(&committee.members, pwd_guess_shares)
+        .map(|(&rank, pwd_guess_share)| {
+            let mut args = vec![id.as_bytes().to_owned(), pwd_guess_share];
+            args.extend(recovery_request.map(|r| r.as_bytes().to_owned()));
+            (rank, args)
+        })
+        .collect();
+    let res = transport.exec(Uuid::now_v7(), "cancel_recovery", node_args).await?;
+
+    let cancelled: Vec<bool> = parse_outputs(res, &committee.members)?;
+    if cancelled.iter().any(|&c| c != cancelled[0]) {
+        return Err(ClientError::Disagreement("nodes disagree on whether the recovery was cancelled".to_owned()));
+    }
+    Ok(cancelled[0])
+}
+
+// The user's recovery delay and pending recoveries, as the first committee
+// member reports them. Times differ slightly between nodes.
+pub(crate) async fn recovery_status(transport: &impl Transport, id: &str) -> Result<RecoveryState, ClientError> {
+    let committee = fetch_committee(transport).await?;
+    let first = &committee.members[..1];
+
+    let res = transport.exec(Uuid::now_v7(), "recovery_status", same_args(first, vec![id.as_bytes().to_owned()])).await?;
+    Ok(parse_outputs(res, first)?.remove(0))
+}
+
+// Replaces the secret key, keeping the password, after the committee has
+// checked the password. Returns the new secret version, or None if the password
+// was wrong and nothing changed.
+pub(crate) async fn rotate_sk(transport: &impl Transport, id: &str, label: &str, new_sk: &str, pwd: &str) -> Result<Option<u64>, ClientError> {
+    let committee = fetch_committee(transport).await?;
+    let pwd_guess_shares = compute_pwd_guess(DEFAULT_FACTOR, pwd, &committee);
+    let rng = &mut ChaCha20Rng::from_entropy();
+    let (sk_shares, salt, hash) = share_sk(id, new_sk, &committee, rng);
+    let meta = SecretMeta {
+        label: label.to_owned(),
+        kind: SecretKind::Text,
+        created: now_secs(),
+        size: new_sk.len(),
+    };
+    let meta = serde_json::to_vec(&meta)?;
+
+    let node_args = iter::zip(&committee.members, iter::zip(pwd_guess_shares, sk_shares))
+        .map(|(&rank, (pwd_guess_share, sk_share))| (rank, vec![id.as_bytes().to_owned(), pwd_guess_share, sk_share, salt.to_vec(), hash.clone(), meta.clone()]))
+        .collect();
+    let res = transport.exec(Uuid::now_v7(), "rotate_sk", node_args).await?;
+
+    let versions: Vec<Option<u64>> = parse_outputs(res, &committee.members)?;
+    if versions.iter().any(|&v| v != versions[0]) {
+        return Err(ClientError::Disagreement("nodes disagree on the rotated secret version".to_owned()));
+    }
+    Ok(versions[0])
+}
+
+// Moves every user's shares to a new committee made up of `members` (world
+// ranks) with the given threshold, then checks that all nodes switched over.
+// Returns the new committee and how many users it holds.
+pub(crate) async fn reshare(transport: &impl Transport, threshold: usize, members: Vec<usize>) -> Result<(Committee, usize), ClientError> {
+    let before = committee_statuses(transport).await?;
+    let old = before[0].committee.clone();
+    if before.iter().any(|status| status.committee != old) {
+        return Err(ClientError::Disagreement("nodes disagree on the current committee".to_owned()));
+    }
+    let users = before[old.members[0]].users.clone();
+    if old.members.iter().any(|&m| before[m].users != users) {
+        return Err(ClientError::Disagreement("committee members hold different sets of users".to_owned()));
+    }
+
+    let new = Committee {
+        epoch: old.epoch + 1,
+        threshold,
+        members,
+    };
+    new.validate(transport.num_nodes()).map_err(ClientError::InvalidInput)?;
+
+    let ranks = all_ranks(transport);
+    let res = transport.exec(Uuid::now_v7(), "reshare", same_args(&ranks, vec![serde_json::to_vec(&new)?])).await?;
+    node_outputs(res, &ranks)?;
+
+    let after = committee_statuses(transport).await?;
+    for (rank, status) in after.iter().enumerate() {
+        if status.committee != new {
+            return Err(ClientError::Disagreement(format!("node {} did not switch to epoch {}", rank, new.epoch)));
+        }
+        let expected: &[String] = if new.members.contains(&rank) { &users } else { &[] };
+        if status.users != expected {
+            return Err(ClientError::Disagreement(format!("node {} holds {} users, expected {}", rank, status.users.len(), expected.len())));
+        }
+    }
+
+    Ok((new, users.len()))
+}
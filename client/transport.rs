@@ -0,0 +1,161 @@
+// Copyright 2023 The Dots Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// How the client reaches the nodes: over gRPC to dots servers, or through any
+// other transport, such as the simulated nodes the server tests run.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use dotspb::dec_exec::dec_exec_client::DecExecClient;
+use futures::future::{self, BoxFuture};
+use tonic::transport::Channel;
+use uuid::Uuid;
+
+use crate::util::ServerError;
+use crate::ClientError;
+
+const APP_NAME: &str = "skrecovery";
+
+/// Each node's output, once all of them have answered.
+pub type Outputs<'a> = BoxFuture<'a, Result<Vec<Vec<u8>>, ClientError>>;
+
+/// The nodes of a deployment, ranked from 0.
+pub trait Transport: Sync {
+    fn num_nodes(&self) -> usize;
+
+    /// Runs a request on the nodes at the given ranks at once, each with its
+    /// own arguments, and returns what each of them output in the same order.
+    /// Fails with `ClientError::Transport` if any node could not be reached;
+    /// errors the nodes report are outputs like any other.
+    fn exec<'a>(&'a self, request_id: Uuid, func_name: &'a str, node_args: Vec<(usize, Vec<Vec<u8>>)>) -> Outputs<'a>;
+}
+
+fn uuid_to_uuidpb(id: Uuid) -> dotspb::dec_exec::Uuid {
+    dotspb::dec_exec::Uuid {
+        hi: (id.as_u128() >> 64) as u64,
+        lo: id.as_u128() as u64,
+    }
+}
+
+/// Dots servers, one connection per node in rank order.
+pub struct Grpc {
+    clients: Vec<DecExecClient<Channel>>,
+}
+
+impl Grpc {
+    pub async fn connect(node_addrs: &[String]) -> Result<Grpc, ClientError> {
+        let clients = future::join_all(
+                node_addrs
+                    .iter()
+                    .map(|addr| async move {
+                        DecExecClient::connect(addr.clone())
+                            .await
+                            .map_err(|e| ClientError::Transport(format!("could not connect to {}: {}", addr, e)))
+                    })
+            )
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Grpc { clients })
+    }
+}
+
+impl Transport for Grpc {
+    fn num_nodes(&self) -> usize {
+        self.clients.len()
+    }
+
+    fn exec<'a>(&'a self, request_id: Uuid, func_name: &'a str, node_args: Vec<(usize, Vec<Vec<u8>>)>) -> Outputs<'a> {
+        Box::pin(async move {
+            if let Some((rank, _)) = node_args.iter().find(|(rank, _)| *rank >= self.clients.len()) {
+                return Err(ClientError::Transport(format!("no node {}", rank)));
+            }
+            // channels are cheap to clone and multiplex requests
+            let res = future::join_all(
+                    node_args
+                        .into_iter()
+                        .map(|(rank, args)| {
+                            let mut client = self.clients[rank].clone();
+                            async move {
+                                client.exec(tonic::Request::new(dotspb::dec_exec::App {
+                                    app_name: APP_NAME.to_owned(),
+                                    app_uid: 0,
+                                    request_id: Some(uuid_to_uuidpb(request_id)),
+                                    client_id: "".to_owned(),
+                                    func_name: func_name.to_owned(),
+                                    in_files: vec![],
+                                    out_files: vec![],
+                                    args,
+                                }))
+                                .await
+                                .map_err(|status| ClientError::Transport(format!("node {}: {}", rank, status.message())))
+                            }
+                        })
+                )
+                .await
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(res.into_iter().map(|res| res.into_inner().output).collect())
+        })
+    }
+}
+
+/// A request sent through `Recorded`, and how it went.
+#[derive(Clone, Debug)]
+pub struct Call {
+    pub func_name: String,
+    pub elapsed: Duration,
+    /// The rank of each node the request went to, with the error it returned
+    /// if it did, or why the nodes could not be reached.
+    pub nodes: Result<Vec<(usize, Option<ServerError>)>, String>,
+}
+
+/// Another transport, keeping a record of every request made through it.
+pub struct Recorded<T> {
+    inner: T,
+    calls: Mutex<Vec<Call>>,
+}
+
+impl<T> Recorded<T> {
+    pub fn new(inner: T) -> Recorded<T> {
+        Recorded { inner, calls: Mutex::new(Vec::new()) }
+    }
+
+    /// The requests made so far, in the order they finished.
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls.lock().map_or_else(|poisoned| poisoned.into_inner().clone(), |calls| calls.clone())
+    }
+}
+
+impl<T: Transport> Transport for Recorded<T> {
+    fn num_nodes(&self) -> usize {
+        self.inner.num_nodes()
+    }
+
+    fn exec<'a>(&'a self, request_id: Uuid, func_name: &'a str, node_args: Vec<(usize, Vec<Vec<u8>>)>) -> Outputs<'a> {
+        Box::pin(async move {
+            let ranks: Vec<usize> = node_args.iter().map(|(rank, _)| *rank).collect();
+            let start = Instant::now();
+            let res = self.inner.exec(request_id, func_name, node_args).await;
+            let nodes = match &res {
+                Ok(outputs) => Ok(ranks.into_iter().zip(outputs).map(|(rank, output)| (rank, ServerError::from_output(output))).collect()),
+                Err(e) => Err(e.to_string()),
+            };
+            let call = Call { func_name: func_name.to_owned(), elapsed: start.elapsed(), nodes };
+            self.calls.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(call);
+            res
+        })
+    }
+}
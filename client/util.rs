@@ -0,0 +1,1429 @@
+// Copyright 2023 The Dots Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use blake2::{Blake2s256, Blake2b512, Digest};
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce, aead::{Aead, Payload}};
+use p256::{NonZeroScalar, ProjectivePoint, PublicKey, Scalar, U256};
+use elliptic_curve::{Field, PrimeField, ops::Reduce, generic_array::{GenericArray, typenum::U32}, bigint::Encoding, sec1::ToEncodedPoint, subtle::ConstantTimeEq};
+use block_padding::{Pkcs7, Padding};
+use rand::prelude::*;
+#[cfg(test)]
+use rand_chacha::ChaCha20Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::io::IsTerminal;
+use tracing_subscriber::{EnvFilter, field::MakeExt, fmt::{FormatFields, format::{Writer, debug_fn}}};
+use vsss_rs::Share;
+use zeroize::{Zeroize, Zeroizing};
+
+/// Secret material: keys, passwords, shares and anything recovered from them.
+/// Wiped from memory when dropped, and never shown by Debug. Serializes as
+/// the value it holds.
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Secret<T: Zeroize>(Zeroizing<T>);
+
+impl<T: Zeroize> Secret<T> {
+    pub fn new(value: T) -> Secret<T> {
+        Secret(Zeroizing::new(value))
+    }
+}
+
+impl<T: Zeroize> From<T> for Secret<T> {
+    fn from(value: T) -> Secret<T> {
+        Secret::new(value)
+    }
+}
+
+impl<T: Zeroize> std::ops::Deref for Secret<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Zeroize> std::ops::DerefMut for Secret<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: Zeroize> Debug for Secret<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+// Names of fields that may carry secret material, alone or as a suffix after
+// '_' (e.g. `pwd_share`). Their values never reach the logs, whatever the
+// level.
+const REDACTED_FIELDS: [&str; 10] = ["sk", "pwd", "password", "secret", "share", "shares", "seed", "prg", "plaintext", "code"];
+
+fn is_redacted(field: &str) -> bool {
+    REDACTED_FIELDS.iter().any(|name| field == *name || field.ends_with(&format!("_{}", name)))
+}
+
+// Formats event and span fields, replacing the values of redacted ones.
+fn redacting_fields() -> impl for<'w> FormatFields<'w> + 'static {
+    debug_fn(|writer: &mut Writer<'_>, field: &tracing::field::Field, value: &dyn Debug| {
+        match field.name() {
+            "message" => write!(writer, "{:?}", value),
+            name if is_redacted(name) => write!(writer, "{}=[redacted]", name),
+            name => write!(writer, "{}={:?}", name, value),
+        }
+    })
+    .delimited(" ")
+}
+
+// Logs to stderr at the levels in the SKRECOVERY_LOG environment variable
+// (e.g. `debug`), or `default_level` if it is unset.
+pub fn init_tracing(default_level: &str) {
+    let filter = EnvFilter::try_from_env("SKRECOVERY_LOG").unwrap_or_else(|_| EnvFilter::new(default_level));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .fmt_fields(redacting_fields())
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal())
+        .init();
+}
+
+// Threshold and size of the committee a fresh deployment starts with. After a
+// committee change the current values live in `Committee` instead.
+pub const THRESHOLD: usize = 2;
+pub const NUM_SERVERS: usize = 5;
+
+/// The set of nodes holding shares for a given epoch. `members` are world
+/// ranks in increasing order; the member at index `i` holds the share with
+/// identifier `i + 1`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Committee {
+    pub epoch: u64,
+    pub threshold: usize,
+    pub members: Vec<usize>,
+}
+
+impl Committee {
+    pub fn initial() -> Committee {
+        Committee {
+            epoch: 0,
+            threshold: THRESHOLD,
+            members: (0..NUM_SERVERS).collect(),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn index_of(&self, rank: usize) -> Option<usize> {
+        self.members.iter().position(|&m| m == rank)
+    }
+
+    // Size of the sets A used to build the random hiding term during recovery.
+    pub fn a_size(&self) -> usize {
+        self.size() - self.threshold
+    }
+
+    pub fn validate(&self, world_size: usize) -> Result<(), String> {
+        if self.threshold < 2 {
+            return Err(format!("threshold {} is below 2", self.threshold));
+        }
+        // recovery multiplies two sharings, so 2t shares must be available
+        if self.size() < 2 * self.threshold {
+            return Err(format!("{} members cannot support threshold {}", self.size(), self.threshold));
+        }
+        if self.size() > 255 {
+            return Err(format!("{} members exceed the share identifier space", self.size()));
+        }
+        if !self.members.windows(2).all(|w| w[0] < w[1]) {
+            return Err(String::from("members must be distinct and in increasing order"));
+        }
+        if self.members.iter().any(|&m| m >= world_size) {
+            return Err(format!("members must be ranks below the world size {}", world_size));
+        }
+        Ok(())
+    }
+}
+
+/// Committee configuration as reported by a single node, along with the users
+/// it currently holds records for and the request schema version it checks
+/// arguments against.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CommitteeStatus {
+    pub committee: Committee,
+    pub users: Vec<String>,
+    // nodes from before request schemas report 0
+    #[serde(default)]
+    pub schema_version: u8,
+}
+
+// Version of the arguments every server function takes. Bumped whenever an
+// argument is added, removed or changes form, so that clients can tell they
+// do not speak the nodes' version before sending anything.
+pub const REQUEST_SCHEMA_VERSION: u8 = 2;
+
+// Label of the secret a user gets when none is given, stored under the file
+// names used before users could have several secrets.
+pub const DEFAULT_LABEL: &str = "default";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretKind {
+    // a string split into 31-byte chunks, checked with a salted hash
+    Text,
+    // a P-256 private key, checked against its public key
+    EcKey,
+    // an AEAD key for a ciphertext every member stores
+    Encrypted,
+}
+
+/// Describes one of a user's secrets. Members keep it in the clear so labels
+/// can be listed without recovering anything.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SecretMeta {
+    pub label: String,
+    pub kind: SecretKind,
+    // seconds since the Unix epoch, as reported by the uploading client
+    pub created: u64,
+    // length of the secret in bytes
+    pub size: usize,
+}
+
+pub fn validate_label(label: &str) -> Result<(), String> {
+    let valid_chars = label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if label.is_empty() || label.len() > 64 || !valid_chars {
+        return Err(format!("label {:?} must be 1 to 64 letters, digits, '-' or '_'", label));
+    }
+    Ok(())
+}
+
+// User IDs start the names of the user's files, where '#' delimits labels.
+pub fn validate_user_id(user_id: &str) -> Result<(), String> {
+    let valid_chars = user_id.chars().all(|c| !c.is_control() && !matches!(c, '#' | '/' | '\\'));
+    if user_id.is_empty() || user_id.len() > 256 || !valid_chars {
+        return Err(format!("user ID {:?} must be 1 to 256 bytes without '#', '/', '\\' or control characters", user_id));
+    }
+    Ok(())
+}
+
+// Recovery factor a user gets at registration, stored under the file name used
+// before users could have several.
+pub const DEFAULT_FACTOR: &str = "password";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FactorKind {
+    Password,
+    // a random code generated by the client, meant to be printed
+    BackupCode,
+    // answers separated by '|'
+    SecurityQuestions,
+}
+
+/// One way for a user to authenticate, each with its own password-share slot
+/// on the servers. Factor IDs follow the same rules as secret labels.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FactorMeta {
+    pub id: String,
+    pub kind: FactorKind,
+    pub created: u64,
+}
+
+// Brings a factor value into the form that gets hashed, so that answers and
+// codes match however they are typed.
+pub fn normalize_factor(kind: FactorKind, value: &str) -> String {
+    match kind {
+        FactorKind::Password => value.to_owned(),
+        FactorKind::BackupCode => value
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .map(|c| c.to_ascii_uppercase())
+            .collect(),
+        FactorKind::SecurityQuestions => value
+            .split('|')
+            .map(|answer| answer.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase())
+            .collect::<Vec<_>>()
+            .join("\n"),
+    }
+}
+
+/// A recovery the servers have checked the password for, whose shares are
+/// released once `ready_at` (seconds since the epoch) has passed.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingRecovery {
+    pub request_id: String,
+    pub label: String,
+    pub created: u64,
+    pub ready_at: u64,
+}
+
+/// A shorter recovery delay that takes effect at `effective_at` unless
+/// cancelled, so that a stolen password cannot switch the delay off at once.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DelayChange {
+    pub seconds: u64,
+    pub effective_at: u64,
+}
+
+/// A user's time-delayed recovery policy and the recoveries waiting on it.
+/// A delay of zero means recoveries are not delayed.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecoveryState {
+    pub delay: u64,
+    pub lowering: Option<DelayChange>,
+    pub pending: Vec<PendingRecovery>,
+}
+
+impl RecoveryState {
+    pub fn current_delay(&self, now: u64) -> u64 {
+        match &self.lowering {
+            Some(change) if now >= change.effective_at => change.seconds,
+            _ => self.delay,
+        }
+    }
+
+    // Raising the delay applies at once, lowering it only after the current
+    // delay. Returns when the new delay takes effect.
+    pub fn set_delay(&mut self, seconds: u64, now: u64) -> u64 {
+        let current = self.current_delay(now);
+        if seconds >= current {
+            self.delay = seconds;
+            self.lowering = None;
+            now
+        } else {
+            self.delay = current;
+            let effective_at = now + current;
+            self.lowering = Some(DelayChange { seconds, effective_at });
+            effective_at
+        }
+    }
+}
+
+/// What a node learned from a request, as far as it can tell. Recoveries are
+/// `Ok` whatever the guess, since nodes never learn whether it was right.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Ok,
+    PasswordCorrect,
+    PasswordIncorrect,
+    Error(String),
+}
+
+/// One record of a node's audit log. Each record commits to the one before
+/// through `prev_hash`, so that editing or dropping a record breaks the chain
+/// from there on. Records carry no client ID, because the dots request does
+/// not pass it on to the application.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub time: u64,
+    pub request_id: String,
+    pub func_name: String,
+    // see audit_user_hash
+    pub user_hash: Option<String>,
+    pub outcome: AuditOutcome,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+pub const AUDIT_VERSION: u8 = 1;
+
+impl AuditEntry {
+    // Hex encoded hash over every field but `hash` itself.
+    pub fn compute_hash(&self) -> String {
+        let body = AuditEntry { hash: String::new(), ..self.clone() };
+        let mut hasher = Blake2s256::new();
+        hasher.update([AUDIT_VERSION]);
+        hasher.update(serde_json::to_vec(&body).unwrap());
+        hex::encode(hasher.finalize())
+    }
+}
+
+// The `prev_hash` of the first record in a log.
+pub fn audit_genesis_hash() -> String {
+    hex::encode([0u8; 32])
+}
+
+// Users appear in audit logs only by a hash of their ID.
+pub fn audit_user_hash(user_id: &str) -> String {
+    let mut hasher = Blake2s256::new();
+    hasher.update([AUDIT_VERSION]);
+    hasher.update(user_id.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+// Checks that a log is a complete chain from its first record. Returns the
+// sequence number of the first record that does not fit.
+pub fn verify_audit_chain(entries: &[AuditEntry]) -> Result<(), u64> {
+    let mut prev_hash = audit_genesis_hash();
+    for (i, entry) in entries.iter().enumerate() {
+        if entry.seq != i as u64 || entry.prev_hash != prev_hash || entry.hash != entry.compute_hash() {
+            return Err(i as u64);
+        }
+        prev_hash = entry.hash.clone();
+    }
+    Ok(())
+}
+
+// Compares the logs of all nodes, indexed by rank. Nodes that saw the same
+// request must agree on what it was and, if both checked a password, on the
+// result. Nodes outside the committee miss most requests, which is fine.
+pub fn audit_inconsistencies(logs: &[Vec<AuditEntry>]) -> Vec<String> {
+    let mut seen: BTreeMap<&str, (usize, &AuditEntry)> = BTreeMap::new();
+    let mut problems = Vec::new();
+    for (rank, log) in logs.iter().enumerate() {
+        for entry in log {
+            let (first_rank, first) = *seen.entry(&entry.request_id).or_insert((rank, entry));
+            if first_rank == rank {
+                continue;
+            }
+            let password_results = [&first.outcome, &entry.outcome]
+                .map(|outcome| matches!(outcome, AuditOutcome::PasswordCorrect | AuditOutcome::PasswordIncorrect));
+            if first.func_name != entry.func_name || first.user_hash != entry.user_hash {
+                problems.push(format!("request {} is logged differently at ranks {} and {}", entry.request_id, first_rank, rank));
+            } else if password_results == [true, true] && first.outcome != entry.outcome {
+                problems.push(format!("request {} has different password results at ranks {} and {}", entry.request_id, first_rank, rank));
+            }
+        }
+    }
+    problems
+}
+
+/// Why a node could not carry out a request. Nodes return it in place of the
+/// function's output, see `ServerError::to_output`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ServerError {
+    UnknownFunction(String),
+    BadArguments(String),
+    NotMember,
+    UnknownUser(String),
+    UnknownSecret(String),
+    UnknownFactor(String),
+    AlreadyExists(String),
+    // the request is well formed but not allowed, e.g. a recovery that is
+    // still delayed
+    Refused(String),
+    SeedsMissing,
+    Storage(String),
+    // the committee could not complete a protocol step together
+    Protocol(String),
+    Internal(String),
+}
+
+// Error outputs are wrapped so that they cannot be mistaken for the output
+// of a function that succeeded.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ErrorOutput {
+    error: ServerError,
+}
+
+impl ServerError {
+    pub fn to_output(&self) -> Vec<u8> {
+        serde_json::to_vec(&ErrorOutput { error: self.clone() }).unwrap()
+    }
+
+    /// The error a node returned, if the output is one.
+    pub fn from_output(output: &[u8]) -> Option<ServerError> {
+        serde_json::from_slice::<ErrorOutput>(output).ok().map(|output| output.error)
+    }
+
+    /// A stable name for the kind of error, for machine-readable output.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ServerError::UnknownFunction(_) => "unknown_function",
+            ServerError::BadArguments(_) => "bad_arguments",
+            ServerError::NotMember => "not_member",
+            ServerError::UnknownUser(_) => "unknown_user",
+            ServerError::UnknownSecret(_) => "unknown_secret",
+            ServerError::UnknownFactor(_) => "unknown_factor",
+            ServerError::AlreadyExists(_) => "already_exists",
+            ServerError::Refused(_) => "refused",
+            ServerError::SeedsMissing => "seeds_missing",
+            ServerError::Storage(_) => "storage",
+            ServerError::Protocol(_) => "protocol",
+            ServerError::Internal(_) => "internal",
+        }
+    }
+}
+
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerError::UnknownFunction(name) => write!(f, "unknown function {}", name),
+            ServerError::BadArguments(reason) => write!(f, "bad arguments: {}", reason),
+            ServerError::NotMember => write!(f, "node is not a committee member"),
+            ServerError::UnknownUser(user_id) => write!(f, "unknown user {}", user_id),
+            ServerError::UnknownSecret(label) => write!(f, "user has no secret labeled {}", label),
+            ServerError::UnknownFactor(factor) => write!(f, "user has no recovery factor {}", factor),
+            ServerError::AlreadyExists(what) => write!(f, "{} already exists", what),
+            ServerError::Refused(reason) => write!(f, "refused: {}", reason),
+            ServerError::SeedsMissing => write!(f, "PRG seeds are missing, run seed_prgs first"),
+            ServerError::Storage(reason) => write!(f, "storage error: {}", reason),
+            ServerError::Protocol(reason) => write!(f, "protocol error: {}", reason),
+            ServerError::Internal(reason) => write!(f, "internal error: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+/// The errors some nodes returned for a request, with the rank of each.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NodeErrors(pub Vec<(usize, ServerError)>);
+
+impl std::fmt::Display for NodeErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (rank, error)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+            write!(f, "node {}: {}", rank, error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for NodeErrors {}
+
+/// What a committee member returns from `skrecovery`. Users registered with
+/// an EC private key have a public key instead of a salt and hash, and users
+/// registered in hybrid mode have the ciphertext their shared key opens.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecoveryOutput {
+    pub shares: Secret<Vec<Vec<u8>>>,
+    pub salt: Vec<u8>,
+    pub skhash: Vec<u8>,
+    pub pubkey: Option<Vec<u8>>,
+    pub ciphertext: Option<Vec<u8>>,
+}
+
+/// Leads the binary encodings of shares and recovery outputs, so that their
+/// layout can change. Data from before them is JSON, which starts with `[` or
+/// `{` instead.
+pub const WIRE_VERSION: u8 = 1;
+
+const SHARE_LEN: usize = 33;
+
+fn push_field(out: &mut Vec<u8>, field: &[u8]) {
+    out.extend_from_slice(&(field.len() as u32).to_be_bytes());
+    out.extend_from_slice(field);
+}
+
+// Splits length-prefixed fields off the front of encoded data.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn next(&mut self) -> Result<&'a [u8], String> {
+        let (len, rest) = self.0.split_first_chunk::<4>().ok_or("truncated field length")?;
+        let len = u32::from_be_bytes(*len) as usize;
+        if rest.len() < len {
+            return Err(format!("field of {} bytes with {} left", len, rest.len()));
+        }
+        let (field, rest) = rest.split_at(len);
+        self.0 = rest;
+        Ok(field)
+    }
+}
+
+fn share_records(records: &[u8]) -> Result<impl Iterator<Item = &[u8]>, String> {
+    if !records.len().is_multiple_of(SHARE_LEN) {
+        return Err(format!("{} bytes of shares is not a whole number of {}-byte records", records.len(), SHARE_LEN));
+    }
+    Ok(records.chunks_exact(SHARE_LEN))
+}
+
+/// Encodes shares as the version byte followed by a 33-byte record for each.
+pub fn encode_shares(shares: &[Share<33>]) -> Zeroizing<Vec<u8>> {
+    let mut out = Zeroizing::new(Vec::with_capacity(1 + shares.len() * SHARE_LEN));
+    out.push(WIRE_VERSION);
+    for share in shares {
+        out.extend_from_slice(share.as_ref());
+    }
+    out
+}
+
+/// Decodes what `encode_shares` wrote, or a JSON array of shares.
+pub fn decode_shares(data: &[u8]) -> Result<Vec<Share<33>>, String> {
+    match data.split_first() {
+        Some((b'[', _)) => serde_json::from_slice(data).map_err(|e| e.to_string()),
+        Some((&WIRE_VERSION, records)) => Ok(share_records(records)?.map(|record| Share(record.try_into().unwrap())).collect()),
+        Some((version, _)) => Err(format!("unknown encoding version {}", version)),
+        None => Err("no shares".to_owned()),
+    }
+}
+
+impl RecoveryOutput {
+    /// The version byte, then the shares as one length-prefixed field of
+    /// 33-byte records, then the salt, hash, public key and ciphertext as
+    /// length-prefixed fields, empty where missing. Lengths are 4 bytes, big
+    /// endian.
+    pub fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        let mut out = Zeroizing::new(vec![WIRE_VERSION]);
+        push_field(&mut out, &Zeroizing::new(self.shares.concat()));
+        push_field(&mut out, &self.salt);
+        push_field(&mut out, &self.skhash);
+        push_field(&mut out, self.pubkey.as_deref().unwrap_or_default());
+        push_field(&mut out, self.ciphertext.as_deref().unwrap_or_default());
+        out
+    }
+
+    /// Decodes what `to_bytes` wrote, or the JSON that members sent before:
+    /// this struct, or the `(shares, salt, skhash)` tuple before that.
+    pub fn from_bytes(data: &[u8]) -> Result<RecoveryOutput, String> {
+        match data.first() {
+            Some(b'{') => serde_json::from_slice(data).map_err(|e| e.to_string()),
+            Some(b'[') => {
+                let (shares, salt, skhash): (Vec<Vec<u8>>, Vec<u8>, Vec<u8>) = serde_json::from_slice(data).map_err(|e| e.to_string())?;
+                Ok(RecoveryOutput { shares: Secret::new(shares), salt, skhash, pubkey: None, ciphertext: None })
+            }
+            Some(&WIRE_VERSION) => {
+                let mut fields = Fields(&data[1..]);
+                let shares = Secret::new(share_records(fields.next()?)?.map(|record| record.to_vec()).collect());
+                let salt = fields.next()?.to_vec();
+                let skhash = fields.next()?.to_vec();
+                let pubkey = Some(fields.next()?.to_vec()).filter(|pubkey| !pubkey.is_empty());
+                let ciphertext = Some(fields.next()?.to_vec()).filter(|ciphertext| !ciphertext.is_empty());
+                if !fields.0.is_empty() {
+                    return Err(format!("{} bytes after the last field", fields.0.len()));
+                }
+                Ok(RecoveryOutput { shares, salt, skhash, pubkey, ciphertext })
+            }
+            None => Err("empty recovery output".to_owned()),
+            Some(version) => Err(format!("unknown encoding version {}", version)),
+        }
+    }
+}
+
+/// What a committee member returns from `sign`: the joint nonce point, the
+/// user's public key and this member's share of the response.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SignatureShare {
+    pub nonce: Vec<u8>,
+    pub pubkey: Vec<u8>,
+    pub share: Vec<u8>,
+}
+
+/// What a committee member returns from `decrypt`: its share of x * E for the
+/// ciphertext's ephemeral key E, masked like a recovery share.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DecryptionShare {
+    pub id: u8,
+    pub point: Vec<u8>,
+}
+
+pub fn scalar_to_share(id: u8, scalar: &Scalar) -> Share<33> {
+    let mut bytes = [0u8; 33];
+    bytes[0] = id;
+    bytes[1..].copy_from_slice(&scalar.to_bytes());
+    Share(bytes)
+}
+
+pub fn share_to_scalar(share: &Share<33>) -> Result<Scalar, String> {
+    share.as_field_element()
+        .map_err(|_| format!("share {} is not a valid field element", share.identifier()))
+}
+
+// Same output format as vsss_rs::Shamir::split_secret, but with the threshold
+// and number of shares chosen at runtime.
+pub fn split_secret(secret: Scalar, threshold: usize, limit: usize, rng: &mut (impl RngCore + CryptoRng)) -> Vec<Share<33>> {
+    let mut coefficients = vec![secret];
+    for _ in 1..threshold {
+        coefficients.push(Scalar::random(&mut *rng));
+    }
+    (1..=limit)
+        .map(|id| {
+            let x = Scalar::from(id as u64);
+            let y = coefficients.iter().rev().fold(Scalar::ZERO, |acc, c| acc * x + c);
+            scalar_to_share(id as u8, &y)
+        })
+        .collect()
+}
+
+// Lagrange coefficients for interpolating at zero from the given identifiers.
+pub fn lagrange_coefficients(ids: &[u8]) -> Vec<Scalar> {
+    ids.iter()
+        .map(|&i| {
+            let xi = Scalar::from(i as u64);
+            ids.iter().filter(|&&j| j != i).fold(Scalar::ONE, |acc, &j| {
+                let xj = Scalar::from(j as u64);
+                acc * xj * (xj - xi).invert().unwrap()
+            })
+        })
+        .collect()
+}
+
+// Interpolates the secret from the first `threshold` shares.
+pub fn combine_shares(shares: &[Share<33>], threshold: usize) -> Option<Scalar> {
+    if shares.len() < threshold {
+        return None;
+    }
+    let shares = &shares[..threshold];
+    let ids: Vec<u8> = shares.iter().map(|s| s.identifier()).collect();
+    if ids.contains(&0) || ids.iter().enumerate().any(|(i, id)| ids[..i].contains(id)) {
+        return None;
+    }
+    let mut secret = Scalar::ZERO;
+    for (share, coeff) in shares.iter().zip(lagrange_coefficients(&ids)) {
+        secret += share.as_field_element::<Scalar>().ok()? * coeff;
+    }
+    Some(secret)
+}
+
+pub fn string_hash_to_nzs(str: &str) -> NonZeroScalar {
+    // make string take up 256 bits by hashing
+    let mut hasher = Blake2s256::new();
+    hasher.update(str.as_bytes());
+    let mut buf = Zeroizing::new([0u8; 32]);
+    hasher.finalize_into_reset(GenericArray::from_mut_slice(&mut buf[..]));
+    
+    // turn bytes into U256
+    let str_uint = U256::from_be_bytes(*buf);
+    // get field element from U256 (Uint for the P256 curve)
+    NonZeroScalar::from_uint(str_uint).unwrap()
+}
+
+pub fn sk_to_field_elts(str: &str) -> Secret<Vec<NonZeroScalar>> {
+    let sk_bytes = str.as_bytes();
+    // sized up front so that the vector never leaves copies behind
+    let mut sk_nzs_vec = Secret::new(Vec::with_capacity(sk_bytes.len() / 31 + 1));
+    // split string into chunks
+    for chunk in sk_bytes.chunks(31) {
+        let pos = chunk.len();
+        let mut block: GenericArray::<u8, U32> = [0u8; 32].into();
+        block[..pos].copy_from_slice(chunk);
+        // pad each chunk to 32 bytes
+        Pkcs7::pad(&mut block, pos);
+        // turn bytes into U256
+        let chunk_uint = U256::from_be_bytes(block.into());
+        block.as_mut_slice().zeroize();
+        // get field element from U256 (Uint for the P256 curve)
+        let chunk_nzs = NonZeroScalar::from_uint(chunk_uint).unwrap();
+        sk_nzs_vec.push(chunk_nzs);
+    }
+    sk_nzs_vec
+}
+
+pub fn field_elts_to_string(scalars: &[Scalar]) -> Secret<String> {
+    let mut sk_combined = Secret::new(String::with_capacity(scalars.len() * 31));
+    for scalar in scalars {
+        let mut bytes: GenericArray::<u8, U32> = scalar.to_bytes();
+        let res = Pkcs7::unpad(&bytes).unwrap();
+        sk_combined.push_str(std::str::from_utf8(res).unwrap());
+        bytes.as_mut_slice().zeroize();
+    }
+    sk_combined
+}
+
+// Layout of the salted hash input. Bump whenever compute_sk_hash changes.
+pub const SKHASH_VERSION: u8 = 1;
+
+// Salted hashes stored before they were versioned are a bare Blake2b-512
+// digest, one byte shorter than a versioned one.
+const LEGACY_SKHASH_LEN: usize = 64;
+
+// Salted hash stored alongside the sk shares, led by the version of its
+// input. The user ID is bound into the input so a record can't be passed off
+// as another user's.
+pub fn compute_sk_hash(user_id: &str, salt: &[u8], sk_vec: &[Scalar]) -> Vec<u8> {
+    let mut hasher = Blake2b512::new();
+    hasher.update([SKHASH_VERSION]);
+    hasher.update((user_id.len() as u64).to_le_bytes());
+    hasher.update(user_id.as_bytes());
+    hasher.update(salt);
+    for scalar in sk_vec {
+        hasher.update(scalar.to_bytes());
+    }
+    let mut hash = vec![SKHASH_VERSION];
+    hash.extend(hasher.finalize());
+    hash
+}
+
+// The hash of records uploaded before SKHASH_VERSION, which did not bind the
+// user ID. Only used to verify such records.
+fn compute_legacy_sk_hash(salt: &[u8], sk_vec: &[Scalar]) -> Vec<u8> {
+    let mut hasher = Blake2b512::new();
+    hasher.update(salt);
+    for scalar in sk_vec {
+        hasher.update(scalar.to_bytes());
+    }
+    hasher.finalize().to_vec()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SkCheck {
+    Valid,
+    Invalid,
+    // Positions of the nodes whose verification data differs from what most
+    // nodes reported. Nothing is compared against the recovered key in this case.
+    Inconsistent(Vec<usize>),
+}
+
+// Returns the value most nodes reported, if any, and the positions of the
+// nodes that reported something else.
+fn majority<T: PartialEq + Copy>(reported: &[T]) -> Option<(T, Vec<usize>)> {
+    let value = *reported
+        .iter()
+        .max_by_key(|r| reported.iter().filter(|other| other == r).count())?;
+    let disagreeing = reported
+        .iter()
+        .enumerate()
+        .filter(|(_, r)| **r != value)
+        .map(|(i, _)| i)
+        .collect();
+    Some((value, disagreeing))
+}
+
+pub fn verify_sk_hash(user_id: &str, salts: &[&[u8]], hashes: &[&[u8]], sk_vec: &[Scalar]) -> SkCheck {
+    let reported: Vec<(&[u8], &[u8])> = salts.iter().copied().zip(hashes.iter().copied()).collect();
+    let ((salt, hash), disagreeing) = match majority(&reported) {
+        Some(m) => m,
+        None => return SkCheck::Invalid,
+    };
+    if !disagreeing.is_empty() {
+        return SkCheck::Inconsistent(disagreeing);
+    }
+
+    let expected = match hash.len() {
+        LEGACY_SKHASH_LEN => compute_legacy_sk_hash(salt, sk_vec),
+        _ => compute_sk_hash(user_id, salt, sk_vec),
+    };
+    if bool::from(hash.ct_eq(expected.as_slice())) {
+        SkCheck::Valid
+    } else {
+        SkCheck::Invalid
+    }
+}
+
+// Parses a P-256 private key given as 64 hex digits.
+pub fn ec_key_from_hex(hex_key: &str) -> Result<NonZeroScalar, String> {
+    let bytes = hex::decode(hex_key.trim_start_matches("0x"))
+        .map_err(|e| format!("private key is not hex: {}", e))?;
+    if bytes.len() != 32 {
+        return Err(format!("private key is {} bytes, expected 32", bytes.len()));
+    }
+    Option::from(NonZeroScalar::from_repr(*GenericArray::from_slice(&bytes)))
+        .ok_or_else(|| String::from("private key is not a valid P-256 scalar"))
+}
+
+pub fn encode_point(point: &ProjectivePoint) -> Vec<u8> {
+    point.to_affine().to_encoded_point(true).as_bytes().to_vec()
+}
+
+pub fn decode_point(bytes: &[u8]) -> Result<ProjectivePoint, String> {
+    PublicKey::from_sec1_bytes(bytes)
+        .map(|pk| pk.to_projective())
+        .map_err(|_| String::from("not a valid SEC1 P-256 point"))
+}
+
+// SEC1 compressed encoding of the public key for a P-256 private key.
+pub fn ec_public_key(sk: &Scalar) -> Vec<u8> {
+    encode_point(&(ProjectivePoint::GENERATOR * sk))
+}
+
+// Interpolates in the exponent: given shares s_i * P with their identifiers,
+// returns s * P. Same rules as combine_shares.
+pub fn combine_points(shares: &[(u8, ProjectivePoint)], threshold: usize) -> Option<ProjectivePoint> {
+    if shares.len() < threshold {
+        return None;
+    }
+    let shares = &shares[..threshold];
+    let ids: Vec<u8> = shares.iter().map(|(id, _)| *id).collect();
+    if ids.contains(&0) || ids.iter().enumerate().any(|(i, id)| ids[..i].contains(id)) {
+        return None;
+    }
+    Some(shares.iter().zip(lagrange_coefficients(&ids)).fold(ProjectivePoint::IDENTITY, |acc, ((_, point), coeff)| {
+        acc + *point * coeff
+    }))
+}
+
+// Layout of the Schnorr challenge input. Bump whenever schnorr_challenge changes.
+pub const SCHNORR_VERSION: u8 = 1;
+
+// Schnorr signatures over P-256 are the compressed nonce point R followed by
+// s, with s * G = R + e * P for this challenge e.
+pub fn schnorr_challenge(nonce: &[u8], pubkey: &[u8], msg: &[u8]) -> Scalar {
+    let mut hasher = Blake2s256::new();
+    hasher.update([SCHNORR_VERSION]);
+    hasher.update(nonce);
+    hasher.update(pubkey);
+    hasher.update(msg);
+    let mut buf = [0u8; 32];
+    hasher.finalize_into(GenericArray::from_mut_slice(&mut buf));
+    Scalar::from_uint_reduced(U256::from_be_bytes(buf))
+}
+
+pub fn verify_schnorr(pubkey: &[u8], msg: &[u8], sig: &[u8]) -> bool {
+    if sig.len() != 33 + 32 {
+        return false;
+    }
+    let (nonce, s) = sig.split_at(33);
+    let (nonce_point, pubkey_point) = match (decode_point(nonce), decode_point(pubkey)) {
+        (Ok(nonce_point), Ok(pubkey_point)) => (nonce_point, pubkey_point),
+        _ => return false,
+    };
+    let s: Scalar = match Option::from(Scalar::from_repr(*GenericArray::from_slice(s))) {
+        Some(s) => s,
+        None => return false,
+    };
+    let e = schnorr_challenge(nonce, pubkey, msg);
+    ProjectivePoint::GENERATOR * s == nonce_point + pubkey_point * e
+}
+
+// Signs locally with a whole private key, as guardians do.
+pub fn schnorr_sign(sk: &Scalar, msg: &[u8], rng: &mut (impl RngCore + CryptoRng)) -> Vec<u8> {
+    let k = Zeroizing::new(Scalar::random(&mut *rng));
+    let nonce = encode_point(&(ProjectivePoint::GENERATOR * *k));
+    let e = schnorr_challenge(&nonce, &ec_public_key(sk), msg);
+    let mut sig = nonce;
+    sig.extend_from_slice(&(*k + e * sk).to_bytes());
+    sig
+}
+
+/// Guardians who can approve a recovery in place of a password: any
+/// `threshold` of them, identified by hex encoded compressed P-256 public
+/// keys.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GuardianPolicy {
+    pub threshold: usize,
+    pub guardians: Vec<String>,
+}
+
+impl GuardianPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.threshold == 0 || self.threshold > self.guardians.len() {
+            return Err(format!("guardian threshold {} out of range for {} guardians", self.threshold, self.guardians.len()));
+        }
+        for (i, guardian) in self.guardians.iter().enumerate() {
+            let pubkey = hex::decode(guardian).map_err(|e| format!("guardian key is not hex: {}", e))?;
+            decode_point(&pubkey)?;
+            if self.guardians[..i].contains(guardian) {
+                return Err(format!("guardian {} listed twice", guardian));
+            }
+        }
+        Ok(())
+    }
+
+    // How many distinct guardians produced one of the signatures over msg.
+    pub fn count_approvals(&self, msg: &[u8], sigs: &[Vec<u8>]) -> usize {
+        self.guardians
+            .iter()
+            .filter_map(|guardian| hex::decode(guardian).ok())
+            .filter(|pubkey| sigs.iter().any(|sig| verify_schnorr(pubkey, msg, sig)))
+            .count()
+    }
+}
+
+pub const GUARDIAN_VERSION: u8 = 1;
+
+// What a guardian signs to approve one recovery request. The request ID is
+// chosen by whoever asks for the recovery and can only be used once.
+pub fn guardian_approval_message(user_id: &str, label: &str, request_id: &str) -> Vec<u8> {
+    let mut msg = vec![GUARDIAN_VERSION];
+    for field in [request_id, label, user_id] {
+        msg.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        msg.extend_from_slice(field.as_bytes());
+    }
+    msg
+}
+
+// Verifies a recovered EC private key against the public keys the nodes
+// stored for it, the counterpart of verify_sk_hash for key registrations.
+pub fn verify_public_key(pubkeys: &[&[u8]], sk_vec: &[Scalar]) -> SkCheck {
+    let (pubkey, disagreeing) = match majority(pubkeys) {
+        Some(m) => m,
+        None => return SkCheck::Invalid,
+    };
+    if !disagreeing.is_empty() {
+        return SkCheck::Inconsistent(disagreeing);
+    }
+
+    match sk_vec {
+        [sk] if ec_public_key(sk) == pubkey => SkCheck::Valid,
+        _ => SkCheck::Invalid,
+    }
+}
+
+// Layout of the hybrid mode ciphertext and its associated data. Bump whenever
+// encrypt_secret changes.
+pub const CIPHERTEXT_VERSION: u8 = 1;
+const NONCE_LEN: usize = 12;
+
+fn aead_cipher(key: &Scalar) -> ChaCha20Poly1305 {
+    let mut key_bytes = key.to_bytes();
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key_bytes));
+    key_bytes.as_mut_slice().zeroize();
+    cipher
+}
+
+fn aead_associated_data(user_id: &str) -> Vec<u8> {
+    let mut aad = vec![CIPHERTEXT_VERSION];
+    aad.extend_from_slice(user_id.as_bytes());
+    aad
+}
+
+// Encrypts a secret of any size for hybrid mode. The key is a random scalar so
+// it can be shared and recovered like a single sk chunk; the output is the
+// version byte, the nonce and the ChaCha20-Poly1305 ciphertext.
+pub fn encrypt_secret(user_id: &str, key: &Scalar, plaintext: &[u8], rng: &mut (impl RngCore + CryptoRng)) -> Vec<u8> {
+    let nonce = rng.gen::<[u8; NONCE_LEN]>();
+    let aad = aead_associated_data(user_id);
+    let sealed = aead_cipher(key)
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad: &aad })
+        .expect("encryption failed");
+
+    let mut ciphertext = vec![CIPHERTEXT_VERSION];
+    ciphertext.extend_from_slice(&nonce);
+    ciphertext.extend_from_slice(&sealed);
+    ciphertext
+}
+
+// Decrypts the ciphertext most nodes reported with the recovered key. The
+// AEAD tag takes the place of the salted hash: a wrong password recovers a
+// wrong key, which fails authentication. The plaintext is empty unless the
+// check is Valid.
+pub fn decrypt_secret(user_id: &str, ciphertexts: &[&[u8]], sk_vec: &[Scalar]) -> (SkCheck, Secret<Vec<u8>>) {
+    let (ciphertext, disagreeing) = match majority(ciphertexts) {
+        Some(m) => m,
+        None => return (SkCheck::Invalid, Secret::default()),
+    };
+    if !disagreeing.is_empty() {
+        return (SkCheck::Inconsistent(disagreeing), Secret::default());
+    }
+
+    let key = match sk_vec {
+        [key] => key,
+        _ => return (SkCheck::Invalid, Secret::default()),
+    };
+    if ciphertext.len() < 1 + NONCE_LEN || ciphertext[0] != CIPHERTEXT_VERSION {
+        return (SkCheck::Invalid, Secret::default());
+    }
+    let (nonce, sealed) = ciphertext[1..].split_at(NONCE_LEN);
+    let aad = aead_associated_data(user_id);
+    match aead_cipher(key).decrypt(Nonce::from_slice(nonce), Payload { msg: sealed, aad: &aad }) {
+        Ok(plaintext) => (SkCheck::Valid, Secret::new(plaintext)),
+        Err(_) => (SkCheck::Invalid, Secret::default()),
+    }
+}
+
+// Layout of ECIES ciphertexts: the version byte, the compressed ephemeral key
+// E, the nonce and the ChaCha20-Poly1305 ciphertext under a key derived from
+// E and the shared point. Bump whenever ecies_encrypt changes.
+pub const ECIES_VERSION: u8 = 1;
+const ECIES_HEADER_LEN: usize = 1 + 33 + NONCE_LEN;
+
+fn ecies_cipher(ephemeral: &[u8], shared: &ProjectivePoint) -> ChaCha20Poly1305 {
+    let mut hasher = Blake2s256::new();
+    hasher.update([ECIES_VERSION]);
+    hasher.update(ephemeral);
+    hasher.update(encode_point(shared));
+    ChaCha20Poly1305::new(&hasher.finalize())
+}
+
+// Encrypts to a P-256 public key, e.g. one registered with upload_ec_key.
+pub fn ecies_encrypt(pubkey: &[u8], plaintext: &[u8], rng: &mut (impl RngCore + CryptoRng)) -> Result<Vec<u8>, String> {
+    let pubkey_point = decode_point(pubkey)?;
+    let ephemeral_sk = Zeroizing::new(Scalar::random(&mut *rng));
+    let ephemeral = ec_public_key(&ephemeral_sk);
+    let nonce = rng.gen::<[u8; NONCE_LEN]>();
+    let sealed = ecies_cipher(&ephemeral, &(pubkey_point * *ephemeral_sk))
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad: &[ECIES_VERSION] })
+        .map_err(|_| String::from("encryption failed"))?;
+
+    let mut ciphertext = vec![ECIES_VERSION];
+    ciphertext.extend_from_slice(&ephemeral);
+    ciphertext.extend_from_slice(&nonce);
+    ciphertext.extend_from_slice(&sealed);
+    Ok(ciphertext)
+}
+
+// The ephemeral key E of an ECIES ciphertext, which is all the servers need.
+pub fn ecies_ephemeral(ciphertext: &[u8]) -> Result<&[u8], String> {
+    if ciphertext.len() < ECIES_HEADER_LEN || ciphertext[0] != ECIES_VERSION {
+        return Err(String::from("not an ECIES ciphertext"));
+    }
+    Ok(&ciphertext[1..34])
+}
+
+// Decrypts given the shared point x * E, as combined from decryption shares.
+// Returns None if it does not authenticate, i.e. the shared point is wrong.
+pub fn ecies_decrypt(ciphertext: &[u8], shared: &ProjectivePoint) -> Result<Option<Secret<Vec<u8>>>, String> {
+    let ephemeral = ecies_ephemeral(ciphertext)?;
+    let nonce = &ciphertext[34..ECIES_HEADER_LEN];
+    let sealed = &ciphertext[ECIES_HEADER_LEN..];
+    Ok(ecies_cipher(ephemeral, shared)
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: sealed, aad: &[ECIES_VERSION] })
+        .ok()
+        .map(Secret::new))
+}
+
+ #[test]
+fn test_string_hash_to_nzs() {
+    let str1 = String::from("str1");
+    let str2 = String::from("str2");
+    let nz1 = string_hash_to_nzs(&str1);
+    let nz2 = string_hash_to_nzs(&str1);
+    let nz3 = string_hash_to_nzs(&str2);
+
+    assert_eq!(nz1.ct_eq(&nz2).unwrap_u8(), 1);
+    assert_eq!(nz1.ct_eq(&nz3).unwrap_u8(), 0);
+}
+
+#[test]
+fn test_sk_to_field_elt() {
+    let sk1 = String::from("my_key");
+    let nz1 = sk_to_field_elts(&sk1);
+    let mut scalar_vec = Vec::new();
+    for nz in nz1.iter() {
+        scalar_vec.push(*nz.as_ref());
+    }
+    let sk_recovered = field_elts_to_string(scalar_vec.as_slice());
+    assert!(sk1 == *sk_recovered);
+    assert_eq!(format!("{:?}", sk_recovered), "[redacted]");
+
+    let sk2 = String::from("AD302A6F48F74DD6F9D257F7149E4D06CD8936FE200AF67E08EF88D1CBA4525D");
+    let nz2 = sk_to_field_elts(&sk2);
+    let mut scalar_vec2 = Vec::new();
+    for nz in nz2.iter() {
+        scalar_vec2.push(*nz.as_ref());
+    }
+    let sk_recovered2 = field_elts_to_string(scalar_vec2.as_slice());
+    assert!(sk2 == *sk_recovered2);
+
+    let sk3 = String::from("");
+    let nz3 = sk_to_field_elts(&sk3);
+    let mut scalar_vec3 = Vec::new();
+    for nz in nz3.iter() {
+        scalar_vec3.push(*nz.as_ref());
+    }
+    let sk_recovered3 = field_elts_to_string(scalar_vec3.as_slice());
+    assert!(sk3 == *sk_recovered3);
+}
+
+#[test]
+fn test_split_and_combine() {
+    let rng = &mut ChaCha20Rng::from_entropy();
+    let secret = Scalar::random(&mut *rng);
+    let shares = split_secret(secret, 3, 7, rng);
+    assert_eq!(shares.len(), 7);
+    assert_eq!(combine_shares(&shares, 3), Some(secret));
+    assert_eq!(combine_shares(&shares[4..], 3), Some(secret));
+    assert_eq!(combine_shares(&shares[..2], 3), None);
+
+    // resharing: every holder splits its share again, and a new holder
+    // combines the sub-shares it received with the old Lagrange coefficients
+    let old_ids: Vec<u8> = shares.iter().map(|s| s.identifier()).collect();
+    let coeffs = lagrange_coefficients(&old_ids);
+    let dealt: Vec<Vec<Share<33>>> = shares
+        .iter()
+        .map(|s| split_secret(s.as_field_element().unwrap(), 2, 4, rng))
+        .collect();
+    let new_shares: Vec<Share<33>> = (0..4)
+        .map(|j| {
+            let value = dealt.iter().zip(&coeffs).fold(Scalar::ZERO, |acc, (sub, c)| {
+                acc + sub[j].as_field_element::<Scalar>().unwrap() * c
+            });
+            scalar_to_share(j as u8 + 1, &value)
+        })
+        .collect();
+    assert_eq!(combine_shares(&new_shares[2..], 2), Some(secret));
+}
+
+#[test]
+fn test_verify_sk_hash() {
+    let rng = &mut ChaCha20Rng::from_entropy();
+    let salt = rng.gen::<[u8; 32]>();
+    let scalars = &mut [Scalar::ONE; 3];
+    let hash = compute_sk_hash("alice", &salt, scalars);
+    let salts = [&salt[..]; 3];
+    let hashes = [hash.as_slice(); 3];
+
+    assert_eq!(verify_sk_hash("alice", &salts, &hashes, scalars), SkCheck::Valid);
+    assert_eq!(verify_sk_hash("bob", &salts, &hashes, scalars), SkCheck::Invalid);
+
+    // a hash from before versioning still verifies, for any user
+    let legacy = compute_legacy_sk_hash(&salt, scalars);
+    assert_eq!(verify_sk_hash("bob", &salts, &[legacy.as_slice(); 3], scalars), SkCheck::Valid);
+
+    scalars[0] = Scalar::ZERO;
+    assert_eq!(verify_sk_hash("alice", &salts, &hashes, scalars), SkCheck::Invalid);
+    assert_eq!(verify_sk_hash("alice", &salts, &[legacy.as_slice(); 3], scalars), SkCheck::Invalid);
+}
+
+#[test]
+fn test_verify_public_key() {
+    let sk = ec_key_from_hex("c9afa9d845ba75166b5c215767b1d6934e50c3db36e89b127b8a622b120f6721").unwrap();
+    let pubkey = ec_public_key(sk.as_ref());
+    assert_eq!(pubkey.len(), 33);
+
+    let pubkeys = [pubkey.as_slice(); 3];
+    assert_eq!(verify_public_key(&pubkeys, &[*sk.as_ref()]), SkCheck::Valid);
+    assert_eq!(verify_public_key(&pubkeys, &[Scalar::ONE]), SkCheck::Invalid);
+    assert_eq!(verify_public_key(&pubkeys, &[*sk.as_ref(), Scalar::ONE]), SkCheck::Invalid);
+
+    let other = ec_public_key(&Scalar::ONE);
+    let pubkeys = [pubkey.as_slice(), other.as_slice(), pubkey.as_slice()];
+    assert_eq!(verify_public_key(&pubkeys, &[*sk.as_ref()]), SkCheck::Inconsistent(vec![1]));
+
+    assert!(ec_key_from_hex("abcd").is_err());
+    assert!(ec_key_from_hex(&"00".repeat(32)).is_err());
+}
+
+#[test]
+fn test_verify_sk_hash_inconsistent() {
+    let rng = &mut ChaCha20Rng::from_entropy();
+    let salt = rng.gen::<[u8; 32]>();
+    let other_salt = rng.gen::<[u8; 32]>();
+    let scalars = [Scalar::ONE; 2];
+    let hash = compute_sk_hash("alice", &salt, &scalars);
+
+    // a single node reporting a different salt is flagged, even though the
+    // remaining nodes would verify
+    let salts = [&salt[..], &other_salt[..], &salt[..]];
+    let hashes = [hash.as_slice(); 3];
+    assert_eq!(verify_sk_hash("alice", &salts, &hashes, &scalars), SkCheck::Inconsistent(vec![1]));
+
+    let truncated = &hash[..32];
+    let hashes = [hash.as_slice(), hash.as_slice(), truncated];
+    assert_eq!(verify_sk_hash("alice", &[&salt[..]; 3], &hashes, &scalars), SkCheck::Inconsistent(vec![2]));
+}
+
+#[test]
+fn test_encrypt_and_decrypt_secret() {
+    let rng = &mut ChaCha20Rng::from_entropy();
+    let key = Scalar::random(&mut *rng);
+    let plaintext = vec![7u8; 4096];
+    let ciphertext = encrypt_secret("alice", &key, &plaintext, rng);
+    let ciphertexts = [ciphertext.as_slice(); 3];
+
+    assert_eq!(decrypt_secret("alice", &ciphertexts, &[key]), (SkCheck::Valid, plaintext.into()));
+    assert_eq!(decrypt_secret("bob", &ciphertexts, &[key]).0, SkCheck::Invalid);
+    assert_eq!(decrypt_secret("alice", &ciphertexts, &[Scalar::ONE]).0, SkCheck::Invalid);
+
+    let mut tampered = ciphertext.clone();
+    tampered[20] ^= 1;
+    let ciphertexts = [ciphertext.as_slice(), ciphertext.as_slice(), tampered.as_slice()];
+    assert_eq!(decrypt_secret("alice", &ciphertexts, &[key]).0, SkCheck::Inconsistent(vec![2]));
+}
+
+#[test]
+fn test_schnorr_with_shared_nonce() {
+    let rng = &mut ChaCha20Rng::from_entropy();
+    let sk = Scalar::random(&mut *rng);
+    let pubkey = ec_public_key(&sk);
+    let k = Scalar::random(&mut *rng);
+    let k_shares = split_secret(k, 2, 4, rng);
+    let nonce_shares: Vec<(u8, ProjectivePoint)> = k_shares
+        .iter()
+        .map(|s| (s.identifier(), ProjectivePoint::GENERATOR * s.as_field_element::<Scalar>().unwrap()))
+        .collect();
+    let nonce_point = combine_points(&nonce_shares[1..], 2).unwrap();
+    assert_eq!(nonce_point, ProjectivePoint::GENERATOR * k);
+
+    let nonce = encode_point(&nonce_point);
+    let e = schnorr_challenge(&nonce, &pubkey, b"msg");
+    let mut sig = nonce.clone();
+    sig.extend_from_slice(&(k + e * sk).to_bytes());
+    assert!(verify_schnorr(&pubkey, b"msg", &sig));
+    assert!(!verify_schnorr(&pubkey, b"other", &sig));
+    assert!(!verify_schnorr(&ec_public_key(&Scalar::ONE), b"msg", &sig));
+    assert!(!verify_schnorr(&pubkey, b"msg", &sig[..64]));
+}
+
+#[test]
+fn test_guardian_approvals() {
+    let rng = &mut ChaCha20Rng::from_entropy();
+    let keys: Vec<Scalar> = (0..3).map(|_| Scalar::random(&mut *rng)).collect();
+    let policy = GuardianPolicy {
+        threshold: 2,
+        guardians: keys.iter().map(|sk| hex::encode(ec_public_key(sk))).collect(),
+    };
+    assert!(policy.validate().is_ok());
+    assert!(GuardianPolicy { threshold: 4, ..policy.clone() }.validate().is_err());
+
+    let msg = guardian_approval_message("user", DEFAULT_LABEL, "request");
+    let sig = schnorr_sign(&keys[0], &msg, rng);
+    // the same guardian signing twice still counts once
+    let sigs = vec![sig.clone(), schnorr_sign(&keys[0], &msg, rng)];
+    assert_eq!(policy.count_approvals(&msg, &sigs), 1);
+    let sigs = vec![sig, schnorr_sign(&keys[2], &msg, rng)];
+    assert_eq!(policy.count_approvals(&msg, &sigs), 2);
+    let other = guardian_approval_message("user", DEFAULT_LABEL, "other request");
+    assert_eq!(policy.count_approvals(&other, &sigs), 0);
+}
+
+#[test]
+fn test_recovery_delay_changes() {
+    let mut state = RecoveryState::default();
+    assert_eq!(state.set_delay(100, 10), 10);
+    assert_eq!(state.current_delay(10), 100);
+    // lowering waits out the current delay
+    assert_eq!(state.set_delay(0, 20), 120);
+    assert_eq!(state.current_delay(119), 100);
+    assert_eq!(state.current_delay(120), 0);
+    // raising again applies at once and drops the pending lowering
+    assert_eq!(state.set_delay(200, 30), 30);
+    assert_eq!(state.current_delay(500), 200);
+    assert_eq!(state.lowering, None);
+}
+
+#[test]
+fn test_audit_chain() {
+    let mut entries: Vec<AuditEntry> = Vec::new();
+    for (i, outcome) in [AuditOutcome::Ok, AuditOutcome::PasswordIncorrect, AuditOutcome::Error("x".to_owned())].into_iter().enumerate() {
+        let mut entry = AuditEntry {
+            seq: i as u64,
+            time: 100 + i as u64,
+            request_id: format!("request{}", i),
+            func_name: "skrecovery".to_owned(),
+            user_hash: Some(audit_user_hash("user")),
+            outcome,
+            prev_hash: entries.last().map_or_else(audit_genesis_hash, |e| e.hash.clone()),
+            hash: String::new(),
+        };
+        entry.hash = entry.compute_hash();
+        entries.push(entry);
+    }
+    assert_eq!(verify_audit_chain(&entries), Ok(()));
+
+    let mut edited = entries.clone();
+    edited[1].outcome = AuditOutcome::PasswordCorrect;
+    assert_eq!(verify_audit_chain(&edited), Err(1));
+    let mut dropped = entries.clone();
+    dropped.remove(1);
+    assert_eq!(verify_audit_chain(&dropped), Err(1));
+
+    assert!(audit_inconsistencies(&[entries.clone(), entries.clone(), Vec::new()]).is_empty());
+    assert_eq!(audit_inconsistencies(&[entries.clone(), edited]).len(), 1);
+}
+
+#[test]
+fn test_ecies_with_decryption_shares() {
+    let rng = &mut ChaCha20Rng::from_entropy();
+    let sk = Scalar::random(&mut *rng);
+    let ciphertext = ecies_encrypt(&ec_public_key(&sk), b"attack at dawn", rng).unwrap();
+    let ephemeral = decode_point(ecies_ephemeral(&ciphertext).unwrap()).unwrap();
+
+    let shares: Vec<(u8, ProjectivePoint)> = split_secret(sk, 3, 5, rng)
+        .iter()
+        .map(|s| (s.identifier(), ephemeral * s.as_field_element::<Scalar>().unwrap()))
+        .collect();
+    let shared = combine_points(&shares[2..], 3).unwrap();
+    assert_eq!(ecies_decrypt(&ciphertext, &shared), Ok(Some(b"attack at dawn".to_vec().into())));
+    assert_eq!(ecies_decrypt(&ciphertext, &(ephemeral * Scalar::ONE)), Ok(None));
+    assert!(ecies_decrypt(&ciphertext[..20], &shared).is_err());
+}
+
+#[test]
+fn test_validate_label() {
+    assert!(validate_label(DEFAULT_LABEL).is_ok());
+    assert!(validate_label("backup-seed_2").is_ok());
+    assert!(validate_label("").is_err());
+    assert!(validate_label("a#b").is_err());
+    assert!(validate_label("../sk").is_err());
+    assert!(validate_label(&"a".repeat(65)).is_err());
+}
+
+#[test]
+fn test_normalize_factor() {
+    assert_eq!(normalize_factor(FactorKind::Password, " Pw "), " Pw ");
+    assert_eq!(normalize_factor(FactorKind::BackupCode, "abcd-efgh 1234"), "ABCDEFGH1234");
+    assert_eq!(
+        normalize_factor(FactorKind::SecurityQuestions, " Rex |  New   York"),
+        normalize_factor(FactorKind::SecurityQuestions, "rex|new york"),
+    );
+    assert_ne!(
+        normalize_factor(FactorKind::SecurityQuestions, "a b|c"),
+        normalize_factor(FactorKind::SecurityQuestions, "a|b c"),
+    );
+}
+
+#[test]
+fn test_redacted_fields() {
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+    impl std::io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let buffer = Buffer::default();
+    let writer = buffer.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .fmt_fields(redacting_fields())
+        .with_writer(move || writer.clone())
+        .finish();
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info!(user = "alice", pwd_share = "hunter2", sk = ?[1u8, 2], "uploading");
+    });
+
+    let logged = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    assert!(logged.contains("uploading user=\"alice\" pwd_share=[redacted] sk=[redacted]"));
+    assert!(!logged.contains("hunter2"));
+}
+
+#[test]
+fn test_server_error_output() {
+    let error = ServerError::UnknownUser("alice".to_owned());
+    assert_eq!(ServerError::from_output(&error.to_output()), Some(error));
+    // regular outputs are not taken for errors
+    assert_eq!(ServerError::from_output(b""), None);
+    assert_eq!(ServerError::from_output(b"true"), None);
+    assert_eq!(ServerError::from_output(br#"{"error":"NotMember","extra":1}"#), None);
+
+    let errors = NodeErrors(vec![(1, ServerError::NotMember), (3, ServerError::SeedsMissing)]);
+    assert_eq!(errors.to_string(), "node 1: node is not a committee member; node 3: PRG seeds are missing, run seed_prgs first");
+}
+
+#[test]
+fn test_wire_format() {
+    let shares = vec![scalar_to_share(1, &Scalar::from(7u64)), scalar_to_share(2, &Scalar::from(9u64))];
+    let encoded = encode_shares(&shares);
+    assert_eq!(encoded.len(), 1 + 2 * 33);
+    assert_eq!(decode_shares(&encoded).unwrap(), shares);
+    // share files written as JSON still read
+    assert_eq!(decode_shares(&serde_json::to_vec(&shares).unwrap()).unwrap(), shares);
+    assert!(decode_shares(&encoded[..40]).is_err());
+    assert!(decode_shares(&[2]).is_err());
+
+    let output = RecoveryOutput {
+        shares: Secret::new(shares.iter().map(|share| share.as_ref().to_vec()).collect()),
+        salt: vec![1; 32],
+        skhash: vec![2; 32],
+        pubkey: None,
+        ciphertext: Some(vec![3; 50]),
+    };
+    let encoded = output.to_bytes();
+    assert_eq!(encoded.len(), 1 + 5 * 4 + 2 * 33 + 32 + 32 + 50);
+    let decoded = RecoveryOutput::from_bytes(&encoded).unwrap();
+    assert_eq!((decoded.shares, decoded.salt, decoded.skhash, decoded.pubkey, decoded.ciphertext), (output.shares.clone(), output.salt.clone(), output.skhash.clone(), None, output.ciphertext.clone()));
+    assert!(RecoveryOutput::from_bytes(&encoded[..encoded.len() - 1]).is_err());
+    assert!(RecoveryOutput::from_bytes(&[encoded.as_slice(), &[0]].concat()).is_err());
+
+    // both JSON forms members returned before
+    let decoded = RecoveryOutput::from_bytes(&serde_json::to_vec(&output).unwrap()).unwrap();
+    assert_eq!(decoded.ciphertext, output.ciphertext);
+    let tuple = serde_json::to_vec(&(&*output.shares, &output.salt, &output.skhash)).unwrap();
+    let decoded = RecoveryOutput::from_bytes(&tuple).unwrap();
+    assert_eq!((decoded.shares, decoded.salt, decoded.pubkey), (output.shares, output.salt, None));
+}
@@ -12,35 +12,117 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use blake2::{Blake2s256, Digest};
 use itertools::Itertools;
 use rand::prelude::*;
 use rand_chacha::ChaCha20Rng;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
 use std::error::Error;
-use std::fs;
-use std::io::Error as IoError;
+use std::fs::{self, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{Error as IoError, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::slice::SliceIndex;
+use std::sync::mpsc::{self, Receiver, SendError, Sender, SyncSender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
 use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use libdots::env::Env;
-use libdots::request::Request;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use tracing::{debug, error, info, info_span, warn};
+use uuid::{Uuid, Version};
+use zeroize::{Zeroize, Zeroizing};
 
-#[path = "../util.rs"]
-mod util;
-use util::{NUM_SERVERS, THRESHOLD, NUM_A};
+mod dots;
+#[cfg(test)]
+mod in_process;
+#[cfg(test)]
+mod sim;
+use dots::{Env, Request};
+use skrecovery::util;
+use util::{AuditEntry, AuditOutcome, Committee, CommitteeStatus, DecryptionShare, FactorKind, FactorMeta, GuardianPolicy, PendingRecovery, RecoveryOutput, RecoveryState, Secret, SecretKind, ServerError, SecretMeta, SignatureShare, DEFAULT_FACTOR, DEFAULT_LABEL, REQUEST_SCHEMA_VERSION, init_tracing, validate_label, validate_user_id, audit_genesis_hash, audit_user_hash, combine_points, combine_shares, decode_point, decode_shares, encode_shares, encode_point, guardian_approval_message, schnorr_challenge, lagrange_coefficients, scalar_to_share, share_to_scalar, split_secret};
 
 use elliptic_curve::{Field, ops::Reduce};
-use p256::{Scalar, U256};
+use p256::{ProjectivePoint, Scalar, U256};
 use vsss_rs::Share;
 
+const COMMITTEE_FILE: &str = "committee.json";
+// Hash-chained JSON lines, one per request. Kept across epochs.
+const AUDIT_FILE: &str = "audit.log";
+// The last record of the audit log, so that appending does not read the log.
+const AUDIT_HEAD_FILE: &str = "audit.head";
+
+// Requests that change nothing and reveal nothing about users are left out of
+// the audit log.
+const UNAUDITED: [&str; 2] = ["committee_status", "audit_log"];
+// Requests whose first argument is not a user ID.
+const SYSTEM_FUNCTIONS: [&str; 3] = ["seed_prgs", "reshare", "committee_status"];
+// Requests that exchange messages with the other members. Each runs on a
+// thread of its own as soon as a worker takes it: members take requests in
+// different orders, so a fixed set of workers on each could fill up with
+// requests waiting on each other.
+const INTERACTIVE: [&str; 14] = [
+    "add_secret", "skrecovery", "set_guardians", "set_recovery_delay", "request_recovery", "cancel_recovery", "sign", "delete_user",
+    "change_password", "add_factor", "revoke_factor", "rotate_sk", "seed_prgs", "reshare",
+];
+
+// Serializes appends so that concurrent requests extend the chain in turn.
+static AUDIT_LOCK: Mutex<()> = Mutex::new(());
+
+// Users are spread over a fixed set of locks, see lock_user.
+const USER_LOCK_STRIPES: usize = 64;
+#[allow(clippy::declare_interior_mutable_const)]
+const UNLOCKED: Mutex<()> = Mutex::new(());
+static USER_LOCKS: [Mutex<()>; USER_LOCK_STRIPES] = [UNLOCKED; USER_LOCK_STRIPES];
+
+// Requests handled at once, and accepted requests waiting for a worker. Both
+// can be overridden through the environment.
+const WORKERS_VAR: &str = "SKRECOVERY_WORKERS";
+const QUEUE_VAR: &str = "SKRECOVERY_QUEUE";
+const DEFAULT_WORKERS: usize = 16;
+const DEFAULT_QUEUE_LEN: usize = 64;
+// Interactive requests running at once, per worker. A request waiting here
+// may hold up its counterparts on other members, so this is kept well above
+// what the workers alone could have in flight.
+const INTERACTIVE_PER_WORKER: usize = 4;
+
+// Message tags used between nodes within a single request.
+const SEED_TAG: i32 = 0;
+const RESHARE_TAG: i32 = 1;
+const PWD_CHECK_TAG: i32 = 2;
+const SIGN_NONCE_TAG: i32 = 3;
+
+// What a random value is derived for within a request. Signing needs two.
+const HIDING: &str = "hiding";
+const CHECK: &str = "check";
+const NONCE: &str = "nonce";
+// One empty file per random value derived in this epoch, named after the
+// request ID and purpose, in a directory per HIDINGS_BUCKET seconds of request
+// time.
+const HIDINGS_DIR: &str = "hidings";
+const HIDINGS_BUCKET: u64 = 60;
+// Request IDs carry the time they were made (UUIDv7). Nodes only take IDs
+// this many seconds from their own clock, so that older claims can go.
+const REQUEST_ID_WINDOW: u64 = 600;
+
+// Per-user files. Seeds live next to them as `{i}_prg.json`.
+const USER_FILES: [&str; 5] = ["secrets.json", "factors.json", "guardians.json", "approvals.json", "recovery.json"];
+// Per-secret files, prefixed by secret_prefix.
+const SECRET_FILES: [&str; 6] = ["sk.txt", "skhash.txt", "salt.txt", "pubkey.txt", "ciphertext.txt", "version.txt"];
+
 fn generate_a(num_parties: usize, a_size: usize, rank: usize) -> Vec<Vec<usize>> {
     let other_parties = (0..num_parties - 1)
-    .map(|x| if x < rank as usize { x } else { x + 1 })
+    .map(|x| if x < rank { x } else { x + 1 })
     .collect::<Vec<usize>>();
 
     let mut result = Vec::new();
     for mut v in other_parties.iter().combinations(a_size - 1){
-        let r = rank as usize;
+        let r = rank;
         for j in 0..a_size - 1 {
-            if *v[j] > rank as usize {
+            if *v[j] > rank {
                 v.insert(j, &r);
                 break;
             }
@@ -68,136 +150,1887 @@ fn test_n_sub_a() {
     assert_eq!(n_sub_a(n, a), vec![1,2,3]);
 }
 
-fn handle_request(env: &Env, req: &Request) -> Result<(), Box<dyn Error>> {
+thread_local! {
+    // Where the node running on this thread keeps its files: the working
+    // directory under dots, a directory of its own for each simulated node.
+    static ROOT: RefCell<PathBuf> = const { RefCell::new(PathBuf::new()) };
+}
+
+fn node_path(name: &str) -> PathBuf {
+    ROOT.with(|root| root.borrow().join(name))
+}
+
+fn load_committee() -> Result<Committee, Box<dyn Error>> {
+    match fs::read(node_path(COMMITTEE_FILE)) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Committee::initial()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Every epoch keeps its records and seeds in its own directory so that a
+// committee change can switch over by replacing the committee file alone.
+// Epoch 0 uses the working directory, as deployments did before resharing.
+fn data_dir(epoch: u64) -> PathBuf {
+    if epoch == 0 {
+        node_path(".")
+    } else {
+        node_path(&format!("epoch{}", epoch))
+    }
+}
+
+fn data_path(committee: &Committee, name: &str) -> PathBuf {
+    data_dir(committee.epoch).join(name)
+}
+
+fn write_atomic(path: &Path, data: &[u8]) -> Result<(), IoError> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)
+}
+
+fn read_optional(path: &Path) -> Result<Option<Vec<u8>>, IoError> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), IoError> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// The default secret keeps the file names from before users had several.
+fn secret_prefix(user_id: &str, label: &str) -> String {
+    if label == DEFAULT_LABEL {
+        user_id.to_owned()
+    } else {
+        format!("{}#{}#", user_id, label)
+    }
+}
+
+fn secret_path(committee: &Committee, user_id: &str, label: &str, file: &str) -> PathBuf {
+    data_path(committee, &format!("{}{}", secret_prefix(user_id, label), file))
+}
+
+// The user's secrets. Users registered before labels existed have no index
+// and just the default secret, whose metadata is reconstructed.
+fn read_index(committee: &Committee, user_id: &str) -> Result<Vec<SecretMeta>, Box<dyn Error>> {
+    if let Some(data) = read_optional(&data_path(committee, &format!("{}secrets.json", user_id)))? {
+        return Ok(serde_json::from_slice(&data)?);
+    }
+    if !secret_path(committee, user_id, DEFAULT_LABEL, "sk.txt").exists() {
+        return Ok(Vec::new());
+    }
+    let kind = if secret_path(committee, user_id, DEFAULT_LABEL, "pubkey.txt").exists() {
+        SecretKind::EcKey
+    } else if secret_path(committee, user_id, DEFAULT_LABEL, "ciphertext.txt").exists() {
+        SecretKind::Encrypted
+    } else {
+        SecretKind::Text
+    };
+    Ok(vec![SecretMeta { label: DEFAULT_LABEL.to_owned(), kind, created: 0, size: 0 }])
+}
+
+fn find_secret(committee: &Committee, user_id: &str, label: &str) -> Result<SecretMeta, Box<dyn Error>> {
+    read_index(committee, user_id)?
+        .into_iter()
+        .find(|meta| meta.label == label)
+        .ok_or_else(|| ServerError::UnknownSecret(label.to_owned()).into())
+}
+
+fn remove_secret_files(committee: &Committee, user_id: &str, label: &str) -> Result<(), IoError> {
+    for file in SECRET_FILES {
+        remove_if_exists(&secret_path(committee, user_id, label, file))?;
+    }
+    Ok(())
+}
+
+// The default factor keeps the password file name from before users had
+// several factors.
+fn factor_file(user_id: &str, factor: &str) -> String {
+    if factor == DEFAULT_FACTOR {
+        format!("{}pwd.txt", user_id)
+    } else {
+        format!("{}#{}#factor.txt", user_id, factor)
+    }
+}
+
+fn factor_path(committee: &Committee, user_id: &str, factor: &str) -> PathBuf {
+    data_path(committee, &factor_file(user_id, factor))
+}
+
+// The user's recovery factors. Users registered before factors existed have
+// no index and just a password.
+fn read_factors(committee: &Committee, user_id: &str) -> Result<Vec<FactorMeta>, Box<dyn Error>> {
+    if let Some(data) = read_optional(&data_path(committee, &format!("{}factors.json", user_id)))? {
+        return Ok(serde_json::from_slice(&data)?);
+    }
+    if !factor_path(committee, user_id, DEFAULT_FACTOR).exists() {
+        return Ok(Vec::new());
+    }
+    Ok(vec![FactorMeta { id: DEFAULT_FACTOR.to_owned(), kind: FactorKind::Password, created: 0 }])
+}
+
+fn write_factors(dir: &Path, user_id: &str, factors: &[FactorMeta]) -> Result<(), Box<dyn Error>> {
+    write_atomic(&dir.join(format!("{}factors.json", user_id)), &serde_json::to_vec(factors)?)?;
+    Ok(())
+}
+
+fn read_guardians(committee: &Committee, user_id: &str) -> Result<Option<GuardianPolicy>, Box<dyn Error>> {
+    match read_optional(&data_path(committee, &format!("{}guardians.json", user_id)))? {
+        Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        None => Ok(None),
+    }
+}
+
+// Recovery request IDs already used with guardian approvals, so that the
+// approvals cannot be replayed.
+fn read_used_approvals(committee: &Committee, user_id: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    match read_optional(&data_path(committee, &format!("{}approvals.json", user_id)))? {
+        Some(data) => Ok(serde_json::from_slice(&data)?),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn write_guardians(dir: &Path, user_id: &str, guardians: &Option<GuardianPolicy>, used_approvals: &[String]) -> Result<(), Box<dyn Error>> {
+    if let Some(policy) = guardians {
+        write_atomic(&dir.join(format!("{}guardians.json", user_id)), &serde_json::to_vec(policy)?)?;
+    }
+    if !used_approvals.is_empty() {
+        write_atomic(&dir.join(format!("{}approvals.json", user_id)), &serde_json::to_vec(used_approvals)?)?;
+    }
+    Ok(())
+}
+
+fn read_recovery_state(committee: &Committee, user_id: &str) -> Result<RecoveryState, Box<dyn Error>> {
+    match read_optional(&data_path(committee, &format!("{}recovery.json", user_id)))? {
+        Some(data) => Ok(serde_json::from_slice(&data)?),
+        None => Ok(RecoveryState::default()),
+    }
+}
+
+fn write_recovery_state(dir: &Path, user_id: &str, state: &RecoveryState) -> Result<(), Box<dyn Error>> {
+    if *state != RecoveryState::default() {
+        write_atomic(&dir.join(format!("{}recovery.json", user_id)), &serde_json::to_vec(state)?)?;
+    } else {
+        remove_if_exists(&dir.join(format!("{}recovery.json", user_id)))?;
+    }
+    Ok(())
+}
+
+// Changes to a user's records, and reads that span several of their files,
+// happen under this lock. It must not be held while waiting on other members:
+// two members taking requests for the same user in opposite orders would
+// wait on each other forever.
+fn lock_user(user_id: &str) -> MutexGuard<'static, ()> {
+    let mut hasher = DefaultHasher::new();
+    user_id.hash(&mut hasher);
+    USER_LOCKS[hasher.finish() as usize % USER_LOCK_STRIPES].lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// With a recovery delay, a stolen password could otherwise skip the delay by
+// adding a guardian or factor, or lock the owner out of cancelling by changing
+// the password. Such changes wait until the delay has been lowered to zero,
+// which itself takes the current delay.
+fn refuse_if_delayed(committee: &Committee, user_id: &str) -> Result<(), Box<dyn Error>> {
+    if read_recovery_state(committee, user_id)?.current_delay(now_secs()?) > 0 {
+        return Err(ServerError::Refused("recovery is delayed, lower the delay to zero first".to_owned()).into());
+    }
+    Ok(())
+}
+
+fn now_secs() -> Result<u64, Box<dyn Error>> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+// Where the audit chain ends, and how long the log was right after that.
+#[derive(Serialize, Deserialize)]
+struct AuditHead {
+    seq: u64,
+    hash: String,
+    len: u64,
+}
+
+// The head of an audit log of `len` bytes. The log itself is only read when
+// there is no head yet, or the node stopped between writing the two.
+fn audit_head(len: u64) -> Result<Option<AuditHead>, Box<dyn Error>> {
+    if let Some(data) = read_optional(&node_path(AUDIT_HEAD_FILE))? {
+        let head: AuditHead = serde_json::from_slice(&data)?;
+        if head.len == len {
+            return Ok(Some(head));
+        }
+    }
+    let log = read_optional(&node_path(AUDIT_FILE))?.unwrap_or_default();
+    match log.split(|&b| b == b'\n').rfind(|line| !line.is_empty()) {
+        Some(line) => {
+            let last: AuditEntry = serde_json::from_slice(line)?;
+            Ok(Some(AuditHead { seq: last.seq, hash: last.hash, len: log.len() as u64 }))
+        }
+        None => Ok(None),
+    }
+}
+
+fn append_audit(req: &dyn Request, outcome: AuditOutcome) -> Result<(), Box<dyn Error>> {
+    let user_hash = match req.args().first() {
+        Some(user_id) if !SYSTEM_FUNCTIONS.contains(&req.func_name()) => Some(audit_user_hash(&String::from_utf8_lossy(user_id))),
+        _ => None,
+    };
+
+    let _guard = AUDIT_LOCK.lock().map_err(|_| "audit log lock poisoned")?;
+    let mut file = OpenOptions::new().create(true).append(true).open(node_path(AUDIT_FILE))?;
+    let len = file.metadata()?.len();
+    let last = audit_head(len)?;
+    let mut entry = AuditEntry {
+        seq: last.as_ref().map_or(0, |head| head.seq + 1),
+        time: now_secs()?,
+        request_id: req.id().to_string(),
+        func_name: req.func_name().to_owned(),
+        user_hash,
+        outcome,
+        prev_hash: last.map_or_else(audit_genesis_hash, |head| head.hash),
+        hash: String::new(),
+    };
+    entry.hash = entry.compute_hash();
+
+    let mut line = serde_json::to_vec(&entry)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+    file.sync_data()?;
+    let head = AuditHead { seq: entry.seq, hash: entry.hash, len: len + line.len() as u64 };
+    write_atomic(&node_path(AUDIT_HEAD_FILE), &serde_json::to_vec(&head)?)?;
+    Ok(())
+}
+
+fn remove_user(committee: &Committee, user_id: &str) -> Result<(), Box<dyn Error>> {
+    for meta in read_index(committee, user_id)? {
+        remove_secret_files(committee, user_id, &meta.label)?;
+    }
+    for factor in read_factors(committee, user_id)? {
+        remove_if_exists(&factor_path(committee, user_id, &factor.id))?;
+    }
+    for file in USER_FILES {
+        remove_if_exists(&data_path(committee, &format!("{}{}", user_id, file)))?;
+    }
+    Ok(())
+}
+
+// Largest argument carrying a whole ciphertext or message, gRPC's default
+// limit for a single message.
+const MAX_BLOB_LEN: usize = 4 << 20;
+
+// What an argument has to look like. Shares must carry the identifier of the
+// member they were sent to.
+#[derive(Clone, Copy)]
+enum ArgKind {
+    UserId,
+    Label,
+    Share,
+    // a share followed by the ID of the factor the guess is for, if not the
+    // password
+    Guess,
+    // a JSON list of shares, as uploads and rotations send
+    Shares,
+    // JSON that has to deserialize to the type the function expects
+    Json(fn(&[u8]) -> Result<(), String>),
+    Point,
+    Number,
+    Bytes { min: usize, max: usize },
+}
+
+struct ArgSpec {
+    name: &'static str,
+    kind: ArgKind,
+}
+
+// The arguments of one function, in order. Only the first `required` have to
+// be given.
+struct Schema {
+    args: &'static [ArgSpec],
+    required: usize,
+    // whether the function acts on this member's shares
+    member: bool,
+}
+
+fn json<T: DeserializeOwned>(arg: &[u8]) -> Result<(), String> {
+    serde_json::from_slice::<T>(arg).map(|_| ()).map_err(|e| e.to_string())
+}
+
+const USER: ArgSpec = ArgSpec { name: "user ID", kind: ArgKind::UserId };
+const GUESS: ArgSpec = ArgSpec { name: "password guess", kind: ArgKind::Guess };
+const LABEL: ArgSpec = ArgSpec { name: "label", kind: ArgKind::Label };
+const REQUEST_ID: ArgSpec = ArgSpec { name: "recovery request ID", kind: ArgKind::Label };
+const PWD_SHARE: ArgSpec = ArgSpec { name: "password share", kind: ArgKind::Share };
+const SK_SHARES: ArgSpec = ArgSpec { name: "sk shares", kind: ArgKind::Shares };
+const SALT: ArgSpec = ArgSpec { name: "salt", kind: ArgKind::Bytes { min: 32, max: 32 } };
+const SKHASH: ArgSpec = ArgSpec { name: "salted hash", kind: ArgKind::Bytes { min: 65, max: 65 } };
+const PUBKEY: ArgSpec = ArgSpec { name: "public key", kind: ArgKind::Point };
+const CIPHERTEXT: ArgSpec = ArgSpec { name: "ciphertext", kind: ArgKind::Bytes { min: 1, max: MAX_BLOB_LEN } };
+const SECRET_META: ArgSpec = ArgSpec { name: "secret metadata", kind: ArgKind::Json(json::<SecretMeta>) };
+
+// The schema of every function, as of REQUEST_SCHEMA_VERSION.
+fn request_schema(func_name: &str) -> Option<Schema> {
+    let (args, required, member): (&'static [ArgSpec], usize, bool) = match func_name {
+        "upload_sk_and_pwd" => (&[USER, SK_SHARES, PWD_SHARE, SALT, SKHASH, SECRET_META], 5, true),
+        "upload_ec_key" => (&[USER, SK_SHARES, PWD_SHARE, PUBKEY, SECRET_META], 4, true),
+        "upload_encrypted" => (&[USER, SK_SHARES, PWD_SHARE, CIPHERTEXT, SECRET_META], 4, true),
+        // the verification data of the new secret depends on its kind
+        "add_secret" => (&[USER, GUESS, SECRET_META, SK_SHARES, ArgSpec { name: "verification data", kind: ArgKind::Bytes { min: 1, max: MAX_BLOB_LEN } }, SKHASH], 5, true),
+        "list_secrets" | "list_guardians" | "recovery_status" | "list_factors" => (&[USER], 1, true),
+        "skrecovery" => (&[USER, GUESS, LABEL, REQUEST_ID], 2, true),
+        "set_guardians" => (&[USER, GUESS, ArgSpec { name: "guardian policy", kind: ArgKind::Json(json::<GuardianPolicy>) }], 3, true),
+        "guardian_recovery" => (&[USER, REQUEST_ID, ArgSpec { name: "approvals", kind: ArgKind::Json(json::<Vec<Vec<u8>>>) }, LABEL], 3, true),
+        "set_recovery_delay" => (&[USER, GUESS, ArgSpec { name: "delay", kind: ArgKind::Number }], 3, true),
+        "request_recovery" => (&[USER, GUESS, REQUEST_ID, LABEL], 3, true),
+        "cancel_recovery" => (&[USER, GUESS, REQUEST_ID], 2, true),
+        "sign" => (&[USER, GUESS, ArgSpec { name: "message", kind: ArgKind::Bytes { min: 0, max: MAX_BLOB_LEN } }, LABEL], 3, true),
+        "decrypt" => (&[USER, GUESS, ArgSpec { name: "ephemeral key", kind: ArgKind::Point }, LABEL], 3, true),
+        "change_password" => (&[USER, GUESS, PWD_SHARE], 3, true),
+        "delete_user" => (&[USER, GUESS], 2, true),
+        "add_factor" => (&[USER, GUESS, ArgSpec { name: "factor metadata", kind: ArgKind::Json(json::<FactorMeta>) }, ArgSpec { name: "factor share", kind: ArgKind::Share }], 4, true),
+        "revoke_factor" => (&[USER, GUESS, ArgSpec { name: "factor ID", kind: ArgKind::Label }], 3, true),
+        "rotate_sk" => (&[USER, GUESS, SK_SHARES, SALT, SKHASH, SECRET_META], 5, true),
+        "seed_prgs" | "committee_status" | "audit_log" => (&[], 0, false),
+        "reshare" => (&[ArgSpec { name: "new committee", kind: ArgKind::Json(json::<Committee>) }], 1, false),
+        _ => return None,
+    };
+    Some(Schema { args, required, member })
+}
+
+fn check_arg(kind: ArgKind, arg: &[u8], id: Option<u8>) -> Result<(), String> {
+    let utf8 = |arg| std::str::from_utf8(arg).map_err(|_| String::from("not UTF-8"));
+    let check_share = |share: &Share<33>| {
+        match id {
+            Some(id) if share.identifier() != id => return Err(format!("share identifier {} is not this member's {}", share.identifier(), id)),
+            _ => {}
+        }
+        share.as_field_element::<Scalar>().map(|_| ()).map_err(|_| String::from("share value is not a P-256 scalar"))
+    };
+    let first_share = |arg: &[u8]| Share::<33>::try_from(&arg[..33]).map_err(|_| String::from("not a share"));
+    match kind {
+        ArgKind::UserId => validate_user_id(utf8(arg)?),
+        ArgKind::Label => validate_label(utf8(arg)?),
+        ArgKind::Share if arg.len() != 33 => Err(format!("{} bytes, expected a 33 byte share", arg.len())),
+        ArgKind::Share => check_share(&first_share(arg)?),
+        ArgKind::Guess if arg.len() < 33 => Err(format!("{} bytes, expected a 33 byte share", arg.len())),
+        ArgKind::Guess => {
+            if arg.len() > 33 {
+                validate_label(utf8(&arg[33..])?)?;
+            }
+            check_share(&first_share(arg)?)
+        }
+        ArgKind::Shares => {
+            let shares: Vec<Share<33>> = serde_json::from_slice(arg).map_err(|e| e.to_string())?;
+            if shares.is_empty() {
+                return Err(String::from("no shares"));
+            }
+            shares.iter().try_for_each(check_share)
+        }
+        ArgKind::Json(check) => check(arg),
+        ArgKind::Point => decode_point(arg).map(|_| ()),
+        ArgKind::Number => utf8(arg)?.parse::<u64>().map(|_| ()).map_err(|e| e.to_string()),
+        ArgKind::Bytes { min, max } if arg.len() < min || arg.len() > max => {
+            Err(format!("{} bytes, expected {} to {}", arg.len(), min, max))
+        }
+        ArgKind::Bytes { .. } => Ok(()),
+    }
+}
+
+// Rejects a request whose arguments do not match the function's schema,
+// before anything is read or written for it. `idx` is this node's index in
+// the committee, if it is a member.
+fn check_args(func_name: &str, args: &[Vec<u8>], idx: Option<usize>) -> Result<(), ServerError> {
+    let schema = request_schema(func_name).ok_or_else(|| ServerError::UnknownFunction(func_name.to_owned()))?;
+    if args.len() < schema.required || args.len() > schema.args.len() {
+        return Err(bad_arguments(format!("expected {} to {} arguments, got {}", schema.required, schema.args.len(), args.len())));
+    }
+    let id = match idx {
+        Some(idx) => Some((idx + 1) as u8),
+        None if schema.member => return Err(ServerError::NotMember),
+        None => None,
+    };
+    for (i, (arg, spec)) in args.iter().zip(schema.args).enumerate() {
+        check_arg(spec.kind, arg, id).map_err(|reason| bad_arguments(format!("argument {} ({}): {}", i, spec.name, reason)))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_check_args() {
+    let share = |id: u8| scalar_to_share(id, &Scalar::one()).as_ref().to_vec();
+    let mut guess = share(2);
+    guess.extend_from_slice(b"backup");
+
+    assert_eq!(check_args("skrecovery", &[b"alice".to_vec(), share(2)], Some(1)), Ok(()));
+    assert_eq!(check_args("skrecovery", &[b"alice".to_vec(), guess, b"default".to_vec()], Some(1)), Ok(()));
+    assert_eq!(check_args("audit_log", &[], None), Ok(()));
+
+    assert_eq!(check_args("nothing", &[], Some(1)), Err(ServerError::UnknownFunction("nothing".to_owned())));
+    assert_eq!(check_args("skrecovery", &[b"alice".to_vec(), share(2)], None), Err(ServerError::NotMember));
+    let rejected = [
+        vec![b"alice".to_vec()],
+        vec![b"alice".to_vec(), share(2), b"default".to_vec(), b"request".to_vec(), b"extra".to_vec()],
+        // another member's share
+        vec![b"alice".to_vec(), share(3)],
+        vec![b"alice".to_vec(), share(2)[..32].to_vec()],
+        // a value past the curve order
+        vec![b"alice".to_vec(), [&[2u8][..], &[0xff; 32]].concat()],
+        vec![b"../alice".to_vec(), share(2)],
+        vec![b"alice".to_vec(), share(2), b"no labels/here".to_vec()],
+    ];
+    for args in rejected {
+        assert!(matches!(check_args("skrecovery", &args, Some(1)), Err(ServerError::BadArguments(_))));
+    }
+    let delay = |seconds: &[u8]| check_args("set_recovery_delay", &[b"alice".to_vec(), share(2), seconds.to_vec()], Some(1));
+    assert_eq!(delay(b"3600"), Ok(()));
+    assert!(delay(b"an hour").is_err());
+}
+
+fn bad_arguments(reason: impl ToString) -> ServerError {
+    ServerError::BadArguments(reason.to_string())
+}
+
+fn arg(args: &[Vec<u8>], i: usize) -> Result<&[u8], ServerError> {
+    args.get(i).map(|arg| arg.as_slice()).ok_or_else(|| bad_arguments(format!("missing argument {}", i)))
+}
+
+fn arg_range<R: SliceIndex<[Vec<u8>], Output = [Vec<u8>]>>(args: &[Vec<u8>], range: R) -> Result<&[Vec<u8>], ServerError> {
+    args.get(range).ok_or_else(|| bad_arguments("missing arguments"))
+}
+
+fn string_arg(args: &[Vec<u8>], i: usize) -> Result<String, ServerError> {
+    String::from_utf8(arg(args, i)?.to_vec()).map_err(|_| bad_arguments(format!("argument {} is not UTF-8", i)))
+}
+
+fn json_arg<T: DeserializeOwned>(args: &[Vec<u8>], i: usize) -> Result<T, ServerError> {
+    serde_json::from_slice(arg(args, i)?).map_err(|e| bad_arguments(format!("argument {}: {}", i, e)))
+}
+
+fn share_arg(args: &[Vec<u8>], i: usize) -> Result<Share<33>, ServerError> {
+    Share::<33>::try_from(arg(args, i)?).map_err(|_| bad_arguments(format!("argument {} is not a share", i)))
+}
+
+// The user ID every function acting on a user takes first.
+fn user_arg(args: &[Vec<u8>]) -> Result<String, ServerError> {
+    string_arg(args, 0)
+}
+
+// As user_arg, for functions that need the user to be registered already.
+fn known_user_arg(committee: &Committee, args: &[Vec<u8>]) -> Result<String, Box<dyn Error>> {
+    let user_id = user_arg(args)?;
+    if read_factors(committee, &user_id)?.is_empty() {
+        return Err(ServerError::UnknownUser(user_id).into());
+    }
+    Ok(user_id)
+}
+
+// The password guess argument: this member's share of the hashed guess,
+// followed by the ID of the factor it is for. Without an ID it is for the
+// password.
+fn guess_arg(args: &[Vec<u8>], i: usize) -> Result<(String, Scalar), ServerError> {
+    let arg = arg(args, i)?;
+    if arg.len() < 33 {
+        return Err(bad_arguments("password guess share is too short"));
+    }
+    let (share, factor) = arg.split_at(33);
+    let factor = match factor {
+        [] => DEFAULT_FACTOR.to_owned(),
+        _ => String::from_utf8(factor.to_vec()).map_err(|_| bad_arguments("factor ID is not UTF-8"))?,
+    };
+    validate_label(&factor).map_err(bad_arguments)?;
+    let share = Share::<33>::try_from(share).map_err(|_| bad_arguments("password guess is not a share"))?;
+    Ok((factor, share_to_scalar(&share).map_err(bad_arguments)?))
+}
+
+// Optional trailing label argument of requests that act on one secret.
+fn label_arg(args: &[Vec<u8>], i: usize) -> Result<String, ServerError> {
+    let label = match args.get(i) {
+        Some(_) => string_arg(args, i)?,
+        None => DEFAULT_LABEL.to_owned(),
+    };
+    validate_label(&label).map_err(bad_arguments)?;
+    Ok(label)
+}
+
+// Optional trailing metadata argument of uploads. Clients that predate labels
+// upload the default secret without it.
+fn meta_arg(args: &[Vec<u8>], i: usize, kind: SecretKind) -> Result<SecretMeta, Box<dyn Error>> {
+    let meta: SecretMeta = match args.get(i) {
+        Some(_) => json_arg(args, i)?,
+        None => SecretMeta {
+            label: DEFAULT_LABEL.to_owned(),
+            kind,
+            created: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            size: 0,
+        },
+    };
+    validate_label(&meta.label).map_err(bad_arguments)?;
+    if meta.kind != kind {
+        return Err(bad_arguments("secret metadata has the wrong kind").into());
+    }
+    Ok(meta)
+}
+
+// Users registered before factors existed only have a password file, and
+// users who revoked their password only have the factor index.
+fn list_users(committee: &Committee) -> Result<Vec<String>, IoError> {
+    let mut users = Vec::new();
+    for entry in fs::read_dir(data_dir(committee.epoch))? {
+        let name = entry?.file_name();
+        let user_id = name.to_str().and_then(|n| n.strip_suffix("pwd.txt").or_else(|| n.strip_suffix("factors.json")));
+        if let Some(user_id) = user_id {
+            users.push(user_id.to_owned());
+        }
+    }
+    users.sort();
+    users.dedup();
+    Ok(users)
+}
+
+// Messages between nodes are length-prefixed since receivers need to size
+// their buffers up front.
+fn send_bytes(req: &dyn Request, data: &[u8], recipient: usize, tag: i32) -> Result<(), Box<dyn Error>> {
+    req.msg_send(&(data.len() as u64).to_le_bytes(), recipient, tag)?;
+    req.msg_send(data, recipient, tag)?;
+    Ok(())
+}
+
+fn recv_bytes(req: &dyn Request, sender: usize, tag: i32) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut len = [0u8; 8];
+    req.msg_recv(&mut len, sender, tag)?;
+    let mut buf = vec![0u8; u64::from_le_bytes(len) as usize];
+    req.msg_recv(&mut buf, sender, tag)?;
+    Ok(buf)
+}
+
+// Agrees on one PRG per set A this member belongs to. The lowest member of
+// each set picks the seed and sends it to the others.
+fn generate_prgs(req: &dyn Request, committee: &Committee, idx: usize) -> Result<Vec<ChaCha20Rng>, Box<dyn Error>> {
+    let a_size = committee.a_size();
+
+    let mut prgs = Vec::new();
+    for v in generate_a(committee.size(), a_size, idx) {
+        let sender = 0; // I think this also works bc the set elements are all in increasing order 
+        debug!(idx, set = ?v, sender = v[sender], "agreeing on a PRG seed");
+        let rng = &mut ChaCha20Rng::from_entropy();
+        let my_prg_seed = Zeroizing::new(rng.gen::<u64>()); // change later?
+
+        let prg_seed = if v[sender] == idx {
+            for &member in &v {
+                if member != idx {
+                    req.msg_send(&my_prg_seed.to_le_bytes(), committee.members[member], SEED_TAG)?;
+                }
+            }
+            my_prg_seed
+        } else {
+            let mut buf = Zeroizing::new([0u8; 8]);
+            req.msg_recv(&mut buf[..], committee.members[v[sender]], SEED_TAG)?;
+            Zeroizing::new(u64::from_le_bytes(*buf))
+        };
+        prgs.push(ChaCha20Rng::seed_from_u64(*prg_seed));
+    }
+    Ok(prgs)
+}
+
+/// A user's shares as held by one node, or the sub-shares of them dealt to a
+/// new committee member during resharing.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+struct UserRecord {
+    user_id: String,
+    factors: Vec<FactorRecord>,
+    secrets: Vec<SecretRecord>,
+    guardians: Option<GuardianPolicy>,
+    used_approvals: Vec<String>,
+    recovery: RecoveryState,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+struct FactorRecord {
+    meta: FactorMeta,
+    share: Share<33>,
+}
+
+/// One labeled secret of a user. EC keys have an empty salt and hash and a
+/// public key instead, hybrid mode secrets a ciphertext.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+struct SecretRecord {
+    meta: SecretMeta,
+    sk_shares: Vec<Share<33>>,
+    salt: Vec<u8>,
+    skhash: Vec<u8>,
+    pubkey: Option<Vec<u8>>,
+    ciphertext: Option<Vec<u8>>,
+    version: u64,
+}
+
+impl UserRecord {
+    // Every share in the record, in a fixed order.
+    fn shares(&self) -> Vec<Share<33>> {
+        let mut shares: Vec<Share<33>> = self.factors.iter().map(|factor| factor.share).collect();
+        for secret in &self.secrets {
+            shares.extend(&secret.sk_shares);
+        }
+        shares
+    }
+
+    // The same record with each share replaced, in the order of shares().
+    fn map_shares(&self, mut f: impl FnMut(usize) -> Share<33>) -> UserRecord {
+        let mut record = self.clone();
+        let mut k = 0;
+        let mut next = || {
+            k += 1;
+            f(k - 1)
+        };
+        for factor in &mut record.factors {
+            factor.share = next();
+        }
+        for secret in &mut record.secrets {
+            for share in &mut secret.sk_shares {
+                *share = next();
+            }
+        }
+        record
+    }
+}
+
+// Builds the record of an uploaded secret from this member's sk shares and
+// the verification data for the secret's kind.
+fn parse_secret(meta: SecretMeta, sk_shares: &[u8], verification: &[Vec<u8>]) -> Result<SecretRecord, Box<dyn Error>> {
+    let mut secret = SecretRecord {
+        meta,
+        sk_shares: serde_json::from_slice(sk_shares).map_err(|e| bad_arguments(format!("sk shares: {}", e)))?,
+        salt: Vec::new(),
+        skhash: Vec::new(),
+        pubkey: None,
+        ciphertext: None,
+        version: 0,
+    };
+    match (secret.meta.kind, verification) {
+        (SecretKind::Text, [salt, skhash]) => {
+            secret.salt = salt.clone();
+            secret.skhash = skhash.clone();
+        }
+        (SecretKind::EcKey, [pubkey]) => {
+            decode_point(pubkey).map_err(bad_arguments)?;
+            secret.pubkey = Some(pubkey.clone());
+        }
+        // every member keeps the whole ciphertext; only its key is shared
+        (SecretKind::Encrypted, [ciphertext]) => secret.ciphertext = Some(ciphertext.clone()),
+        _ => return Err(bad_arguments("wrong verification data for the kind of secret").into()),
+    }
+    Ok(secret)
+}
+
+// Marks the random value for a request and purpose as used. A client reusing
+// a request ID would otherwise get two answers masked by the same value.
+fn claim_hiding(committee: &Committee, request_id: Uuid, purpose: &str) -> Result<(), Box<dyn Error>> {
+    let time = match request_id.get_version() {
+        Some(Version::SortRand) => request_id.get_timestamp().map(|ts| ts.to_unix().0),
+        _ => None,
+    };
+    let time = time.ok_or_else(|| ServerError::Refused(format!("request ID {} does not carry its time", request_id)))?;
+    let now = now_secs()?;
+    if time.abs_diff(now) > REQUEST_ID_WINDOW {
+        return Err(ServerError::Refused(format!("request ID {} is from {}, more than {} seconds from now", request_id, time, REQUEST_ID_WINDOW)).into());
+    }
+
+    let root = data_path(committee, HIDINGS_DIR);
+    let dir = root.join((time / HIDINGS_BUCKET).to_string());
+    if !dir.exists() {
+        fs::create_dir_all(&dir)?;
+        prune_hidings(&root, now)?;
+    }
+    match OpenOptions::new().write(true).create_new(true).open(dir.join(format!("{}-{}", request_id, purpose))) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => Err(ServerError::Refused(format!("request ID {} was already used", request_id)).into()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Removes the claims of request IDs that are refused by now anyway, keeping a
+// bucket to spare for requests that checked the time a little earlier.
+fn prune_hidings(root: &Path, now: u64) -> Result<(), IoError> {
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+        let expired = match entry.file_name().to_str().and_then(|name| name.parse::<u64>().ok()) {
+            Some(bucket) => (bucket + 2) * HIDINGS_BUCKET + REQUEST_ID_WINDOW <= now,
+            // claimed before claims had buckets, under IDs that are refused now
+            None => true,
+        };
+        if expired && entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else if expired {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+// A PRG for a single value, keyed by a seed shared within a set A and by what
+// the value is for.
+fn hiding_rng(prg: &ChaCha20Rng, request_id: Uuid, purpose: &str) -> ChaCha20Rng {
+    let mut seed = prg.get_seed();
+    let mut hasher = Blake2s256::new();
+    hasher.update(seed);
+    hasher.update(request_id.as_bytes());
+    hasher.update(purpose.as_bytes());
+    seed.zeroize();
+    let mut key: [u8; 32] = hasher.finalize().into();
+    let rng = ChaCha20Rng::from_seed(key);
+    key.zeroize();
+    rng
+}
+
+// This member's share of a fresh random value R, derived from the seeds it
+// shares with each set A. The value depends only on the request and purpose,
+// so members agree on it whatever order concurrent requests reach them in,
+// and the seeds never change between reseedings.
+fn random_hiding(committee: &Committee, idx: usize, request_id: Uuid, purpose: &str) -> Result<Zeroizing<Scalar>, Box<dyn Error>> {
+    // Thanks Emma for showing us this neat trick!
+    // https://citeseerx.ist.psu.edu/document?repid=rep1&type=pdf&doi=96317e8e38cc956da308026e5328948ebd9d49ad
+
+    claim_hiding(committee, request_id, purpose)?;
+    let my_as = generate_a(committee.size(), committee.a_size(), idx);
+    let r_a = (0..my_as.len()).map(|i| {
+        let prg_path = data_path(committee, &format!("{}_prg.json", i));
+        let prg_data = Zeroizing::new(read_optional(&prg_path)?.ok_or(ServerError::SeedsMissing)?);
+        let prg: ChaCha20Rng = serde_json::from_slice(&prg_data)?;
+        Ok(Scalar::random(hiding_rng(&prg, request_id, purpose)))
+    }).collect::<Result<Vec<Scalar>, Box<dyn Error>>>()?;
+
+    let f_a = my_as.iter().map(|a| {
+        let mut fa_j = Scalar::one();
+        let factors = n_sub_a(committee.size(), a.to_vec());
+        for f in factors {
+            fa_j *= Scalar::from_uint_reduced(U256::from(idx as u32)) - Scalar::from_uint_reduced(U256::from(f as u8));
+        }
+        fa_j
+    });
+    Ok(Zeroizing::new(f_a.zip(r_a).fold(Scalar::zero(), |prev, f_and_r| prev + f_and_r.0 * f_and_r.1)))
+}
+
+// Checks a password guess without revealing the password: the members open
+// R(PW-PWG) to each other, which is zero exactly when the guess is correct
+// and random otherwise. Every member reaches the same answer.
+fn check_password(req: &dyn Request, committee: &Committee, idx: usize, user_id: &str, factor: &str, pwd_guess_share: Scalar) -> Result<bool, Box<dyn Error>> {
+    debug!(factor, "checking a password guess with the committee");
+    let pwd_share = {
+        let _user = lock_user(user_id);
+        read_pwd_share(committee, user_id, factor)?
+    };
+    let hiding = random_hiding(committee, idx, req.id(), CHECK)?;
+    let masked = scalar_to_share((idx + 1) as u8, &((*pwd_share - pwd_guess_share) * *hiding));
+
+    let mut shares = Vec::new();
+    for (j, &member) in committee.members.iter().enumerate() {
+        if j != idx {
+            req.msg_send(masked.as_ref(), member, PWD_CHECK_TAG)?;
+        }
+    }
+    for (j, &member) in committee.members.iter().enumerate() {
+        if j == idx {
+            shares.push(masked);
+        } else {
+            let mut buf = [0u8; 33];
+            req.msg_recv(&mut buf, member, PWD_CHECK_TAG)?;
+            shares.push(Share(buf));
+        }
+    }
+    // the product has degree 2t - 1
+    let opened = combine_shares(&shares, 2 * committee.threshold).ok_or_else(|| ServerError::Protocol("could not open masked difference".to_owned()))?;
+    Ok(bool::from(opened.is_zero()))
+}
+
+// This member's share of the hashed value of one of the user's factors.
+fn read_pwd_share(committee: &Committee, user_id: &str, factor: &str) -> Result<Zeroizing<Scalar>, Box<dyn Error>> {
+    if !read_factors(committee, user_id)?.iter().any(|meta| meta.id == factor) {
+        return Err(ServerError::UnknownFactor(factor.to_owned()).into());
+    }
+    let pwd_share_data = Zeroizing::new(fs::read(factor_path(committee, user_id, factor))?);
+    Ok(Zeroizing::new(share_to_scalar(&Share::<33>::try_from(pwd_share_data.as_slice())?)?))
+}
+
+// The share of an EC key's private key and its public key.
+fn read_ec_key_share(committee: &Committee, user_id: &str, label: &str) -> Result<(Zeroizing<Scalar>, Vec<u8>), Box<dyn Error>> {
+    let secret = read_secret_record(committee, user_id, find_secret(committee, user_id, label)?)?;
+    match (secret.pubkey, secret.sk_shares.as_slice()) {
+        (Some(pubkey), [sk_share]) => Ok((Zeroizing::new(share_to_scalar(sk_share)?), pubkey)),
+        _ => Err(bad_arguments(format!("secret {} is not an EC key", label)).into()),
+    }
+}
+
+// Opens k * G for a nonce k shared like random_hiding's output, by exchanging
+// the members' k_i * G. Every member reaches the same point and k stays shared.
+fn open_nonce(req: &dyn Request, committee: &Committee, idx: usize, k_share: Scalar) -> Result<ProjectivePoint, Box<dyn Error>> {
+    let my_point = encode_point(&(ProjectivePoint::GENERATOR * k_share));
+    for (j, &member) in committee.members.iter().enumerate() {
+        if j != idx {
+            send_bytes(req, &my_point, member, SIGN_NONCE_TAG)?;
+        }
+    }
+    let mut points = Vec::new();
+    for (j, &member) in committee.members.iter().enumerate() {
+        let point = if j == idx { my_point.clone() } else { recv_bytes(req, member, SIGN_NONCE_TAG)? };
+        points.push(((j + 1) as u8, decode_point(&point)?));
+    }
+    // the nonce has degree t, like R
+    combine_points(&points, committee.threshold + 1).ok_or_else(|| ServerError::Protocol("could not open nonce".to_owned()).into())
+}
+
+// Secrets start at version 0 and move up one with every rotation.
+fn read_version(committee: &Committee, user_id: &str, label: &str) -> Result<u64, Box<dyn Error>> {
+    match fs::read(secret_path(committee, user_id, label, "version.txt")) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn read_secret_record(committee: &Committee, user_id: &str, meta: SecretMeta) -> Result<SecretRecord, Box<dyn Error>> {
+    let label = meta.label.clone();
+    let sk_shares_data = fs::read(secret_path(committee, user_id, &label, "sk.txt"))?;
+    Ok(SecretRecord {
+        meta,
+        sk_shares: decode_shares(&sk_shares_data)?,
+        salt: read_optional(&secret_path(committee, user_id, &label, "salt.txt"))?.unwrap_or_default(),
+        skhash: read_optional(&secret_path(committee, user_id, &label, "skhash.txt"))?.unwrap_or_default(),
+        pubkey: read_optional(&secret_path(committee, user_id, &label, "pubkey.txt"))?,
+        ciphertext: read_optional(&secret_path(committee, user_id, &label, "ciphertext.txt"))?,
+        version: read_version(committee, user_id, &label)?,
+    })
+}
+
+fn write_secret_record(dir: &Path, user_id: &str, secret: &SecretRecord) -> Result<(), Box<dyn Error>> {
+    let prefix = secret_prefix(user_id, &secret.meta.label);
+    write_atomic(&dir.join(format!("{}sk.txt", prefix)), &encode_shares(&secret.sk_shares))?;
+    if !secret.skhash.is_empty() {
+        write_atomic(&dir.join(format!("{}skhash.txt", prefix)), &secret.skhash)?;
+        write_atomic(&dir.join(format!("{}salt.txt", prefix)), &secret.salt)?;
+    }
+    if let Some(pubkey) = &secret.pubkey {
+        write_atomic(&dir.join(format!("{}pubkey.txt", prefix)), pubkey)?;
+    }
+    if let Some(ciphertext) = &secret.ciphertext {
+        write_atomic(&dir.join(format!("{}ciphertext.txt", prefix)), ciphertext)?;
+    }
+    write_atomic(&dir.join(format!("{}version.txt", prefix)), &serde_json::to_vec(&secret.version)?)?;
+    Ok(())
+}
+
+fn write_index(dir: &Path, user_id: &str, index: &[SecretMeta]) -> Result<(), Box<dyn Error>> {
+    write_atomic(&dir.join(format!("{}secrets.json", user_id)), &serde_json::to_vec(index)?)?;
+    Ok(())
+}
+
+fn read_user_record(committee: &Committee, user_id: &str) -> Result<UserRecord, Box<dyn Error>> {
+    Ok(UserRecord {
+        user_id: user_id.to_owned(),
+        factors: read_factors(committee, user_id)?
+            .into_iter()
+            .map(|meta| {
+                let share_data = fs::read(factor_path(committee, user_id, &meta.id))?;
+                Ok(FactorRecord { meta, share: Share::<33>::try_from(share_data.as_slice())? })
+            })
+            .collect::<Result<_, Box<dyn Error>>>()?,
+        secrets: read_index(committee, user_id)?
+            .into_iter()
+            .map(|meta| read_secret_record(committee, user_id, meta))
+            .collect::<Result<_, _>>()?,
+        guardians: read_guardians(committee, user_id)?,
+        used_approvals: read_used_approvals(committee, user_id)?,
+        recovery: read_recovery_state(committee, user_id)?,
+    })
+}
+
+fn write_user_record(dir: &Path, record: &UserRecord) -> Result<(), Box<dyn Error>> {
+    let user_id = &record.user_id;
+    for secret in &record.secrets {
+        write_secret_record(dir, user_id, secret)?;
+    }
+    let index: Vec<SecretMeta> = record.secrets.iter().map(|secret| secret.meta.clone()).collect();
+    write_index(dir, user_id, &index)?;
+    for factor in &record.factors {
+        write_factor(dir, user_id, factor)?;
+    }
+    write_guardians(dir, user_id, &record.guardians, &record.used_approvals)?;
+    write_recovery_state(dir, user_id, &record.recovery)?;
+    let factors: Vec<FactorMeta> = record.factors.iter().map(|factor| factor.meta.clone()).collect();
+    write_factors(dir, user_id, &factors)?;
+    Ok(())
+}
+
+fn write_factor(dir: &Path, user_id: &str, factor: &FactorRecord) -> Result<(), Box<dyn Error>> {
+    write_atomic(&dir.join(factor_file(user_id, &factor.meta.id)), factor.share.as_ref())?;
+    Ok(())
+}
+
+// Registering a user replaces everything they had with a single secret. Used
+// guardian request IDs are kept, so old approvals stay spent.
+fn register_user(committee: &Committee, user_id: &str, pwd_share: &[u8], secret: SecretRecord) -> Result<(), Box<dyn Error>> {
+    let _user = lock_user(user_id);
+    let used_approvals = read_used_approvals(committee, user_id)?;
+    remove_user(committee, user_id)?;
+    let password = FactorRecord {
+        meta: FactorMeta {
+            id: DEFAULT_FACTOR.to_owned(),
+            kind: FactorKind::Password,
+            created: now_secs()?,
+        },
+        share: Share::<33>::try_from(pwd_share)?,
+    };
+    let record = UserRecord {
+        user_id: user_id.to_owned(),
+        factors: vec![password],
+        secrets: vec![secret],
+        guardians: None,
+        used_approvals,
+        recovery: RecoveryState::default(),
+    };
+    write_user_record(&data_dir(committee.epoch), &record)
+}
+
+// Splits each of this node's shares into a sharing for the new committee and
+// returns the records destined for each new member, in member order.
+fn deal_records(records: &[UserRecord], new: &Committee) -> Result<Vec<Vec<UserRecord>>, Box<dyn Error>> {
+    let rng = &mut ChaCha20Rng::from_entropy();
+    let mut dealt: Vec<Vec<UserRecord>> = (0..new.size()).map(|_| Vec::new()).collect();
+    for record in records {
+        let subshares = record.shares().iter()
+            .map(|share| Ok(split_secret(share_to_scalar(share)?, new.threshold, new.size(), rng)))
+            .collect::<Result<Vec<_>, String>>()?;
+        for (j, bundle) in dealt.iter_mut().enumerate() {
+            bundle.push(record.map_shares(|k| subshares[k][j]));
+        }
+    }
+    Ok(dealt)
+}
+
+// Combines the sub-shares dealt by every old member into this node's shares
+// under the new committee. `bundles` is in old member order.
+fn combine_records(bundles: Vec<Vec<UserRecord>>, old: &Committee, new_idx: usize) -> Result<Vec<UserRecord>, Box<dyn Error>> {
+    let old_ids: Vec<u8> = (1..=old.size()).map(|id| id as u8).collect();
+    let coeffs = lagrange_coefficients(&old_ids);
+    let new_id = (new_idx + 1) as u8;
+    let blank = |_| Share([0u8; 33]);
+
+    let mut records = Vec::new();
+    let (first, rest) = bundles.split_first().ok_or_else(|| ServerError::Protocol("no records dealt".to_owned()))?;
+    for (u, template) in first.iter().enumerate() {
+        let dealt: Vec<&UserRecord> = bundles.iter().map(|b| b.get(u)).collect::<Option<_>>()
+            .ok_or_else(|| ServerError::Protocol("old members dealt different users".to_owned()))?;
+        // everything but the shares has to match
+        if dealt.iter().any(|r| r.map_shares(blank) != template.map_shares(blank)) {
+            return Err(ServerError::Protocol(format!("old members disagree on the record for {}", template.user_id)).into());
+        }
+
+        let mut values = vec![Scalar::zero(); template.shares().len()];
+        for (record, coeff) in dealt.iter().zip(&coeffs) {
+            for (value, share) in values.iter_mut().zip(record.shares()) {
+                *value += share_to_scalar(&share)? * coeff;
+            }
+        }
+        records.push(template.map_shares(|k| scalar_to_share(new_id, &values[k])));
+    }
+    if rest.iter().any(|b| b.len() != first.len()) {
+        return Err(ServerError::Protocol("old members dealt different users".to_owned()).into());
+    }
+    Ok(records)
+}
+
+#[test]
+fn test_deal_and_combine_records() {
+    let rng = &mut ChaCha20Rng::from_entropy();
+    let old = Committee { epoch: 0, threshold: 2, members: vec![0, 1, 2, 3] };
+    let new = Committee { epoch: 1, threshold: 2, members: vec![1, 2, 3, 4, 5] };
+    let pwd = Scalar::random(&mut *rng);
+    let sk = [Scalar::random(&mut *rng), Scalar::random(&mut *rng)];
+    let pwd_shares = split_secret(pwd, 2, 4, rng);
+    let sk_shares = [split_secret(sk[0], 2, 4, rng), split_secret(sk[1], 2, 4, rng)];
+
+    let meta = |label: &str| SecretMeta { label: label.to_owned(), kind: SecretKind::Text, created: 0, size: 0 };
+    let secret = |meta, sk_shares| SecretRecord {
+        meta, sk_shares, salt: vec![1], skhash: vec![2], pubkey: None, ciphertext: None, version: 0,
+    };
+    // for each old member, the bundles it deals to each new member
+    let bundles: Vec<Vec<Vec<UserRecord>>> = (0..4)
+        .map(|i| vec![UserRecord {
+            user_id: "alice".to_owned(),
+            factors: vec![FactorRecord {
+                meta: FactorMeta { id: DEFAULT_FACTOR.to_owned(), kind: FactorKind::Password, created: 0 },
+                share: pwd_shares[i],
+            }],
+            secrets: vec![secret(meta(DEFAULT_LABEL), vec![sk_shares[0][i]]), secret(meta("backup"), vec![sk_shares[1][i]])],
+            guardians: None,
+            used_approvals: vec!["request".to_owned()],
+            recovery: RecoveryState::default(),
+        }])
+        .map(|records| deal_records(&records, &new).unwrap())
+        .collect();
+
+    let combined: Vec<UserRecord> = (0..new.size())
+        .map(|j| {
+            let dealt = bundles.iter().map(|b| b[j].clone()).collect();
+            combine_records(dealt, &old, j).unwrap().remove(0)
+        })
+        .collect();
+    let shares: Vec<Vec<Share<33>>> = combined.iter().map(|r| r.shares()).collect();
+    for (k, secret) in [pwd, sk[0], sk[1]].iter().enumerate() {
+        let column: Vec<Share<33>> = shares[3..].iter().map(|s| s[k]).collect();
+        assert_eq!(combine_shares(&column, 2), Some(*secret));
+    }
+    assert_eq!(combined[0].secrets[1].meta.label, "backup");
+
+    // a member dealing a different salt is caught
+    let mut dealt: Vec<Vec<UserRecord>> = bundles.iter().map(|b| b[0].clone()).collect();
+    dealt[1][0].secrets[0].salt = vec![9];
+    assert!(combine_records(dealt, &old, 0).is_err());
+}
+
+// Removes the records and seeds of an epoch this node no longer serves.
+fn remove_epoch_data(committee: &Committee, users: &[String]) -> Result<(), Box<dyn Error>> {
+    if committee.epoch != 0 {
+        return Ok(fs::remove_dir_all(data_dir(committee.epoch))?);
+    }
+    // every member belongs to the same number of sets A
+    let num_prgs = generate_a(committee.size(), committee.a_size(), 0).len();
+    for user_id in users {
+        remove_user(committee, user_id)?;
+    }
+    for i in 0..num_prgs {
+        let _ = fs::remove_file(data_path(committee, &format!("{}_prg.json", i)));
+    }
+    let _ = fs::remove_dir_all(data_path(committee, HIDINGS_DIR));
+    Ok(())
+}
+
+// This member's answer to a recovery: its shares of the secret, each masked by
+// R(PW-PWG) so that only the right password unmasks them. A pending recovery
+// comes with whether the committee found the password guess correct.
+fn release_shares(committee: &Committee, idx: usize, request_id: Uuid, user_id: &str, label: &str, guess: &(String, Scalar), pending: Option<(&str, bool)>) -> Result<RecoveryOutput, Box<dyn Error>> {
+    let _user = lock_user(user_id);
+    find_secret(committee, user_id, label)?;
+
+    // with a recovery delay, only a ready pending request releases
+    // shares, and the right password uses it up
+    let mut state = read_recovery_state(committee, user_id)?;
+    let now = now_secs()?;
+    if state.current_delay(now) > 0 {
+        let (pending_id, correct) = pending.ok_or_else(|| ServerError::Refused("recovery is delayed, request it first".to_owned()))?;
+        let position = state.pending.iter()
+            .position(|pending| pending.request_id == pending_id && pending.label == label)
+            .ok_or_else(|| ServerError::Refused(format!("no pending recovery {} of {}", pending_id, label)))?;
+        if state.pending[position].ready_at > now {
+            return Err(ServerError::Refused(format!("recovery {} is not ready until {}", pending_id, state.pending[position].ready_at)).into());
+        }
+        if correct {
+            state.pending.remove(position);
+            write_recovery_state(&data_dir(committee.epoch), user_id, &state)?;
+        }
+    }
+
+    // compute R(PW-PWG) share locally
+    let sk_shares_data = Zeroizing::new(fs::read(secret_path(committee, user_id, label, "sk.txt"))?);
+    let sk_shares = decode_shares(&sk_shares_data)?;
+
+    let (factor, pwd_guess_share) = guess;
+    let pwd_share = read_pwd_share(committee, user_id, factor)?;
+
+    let random_hiding = random_hiding(committee, idx, request_id, HIDING)?;
+
+    let mut result_vec = Secret::new(Vec::new());
+    for mut sk_share in sk_shares {
+        let id = sk_share.identifier();
+        let share = Zeroizing::new(sk_share.as_field_element::<Scalar>().map_err(|_| ServerError::Storage(format!("share of {} is not a scalar", label)))?);
+        sk_share.0.zeroize();
+        let field_to_write = Zeroizing::new((*pwd_share - pwd_guess_share) * *random_hiding + *share);
+        let mut result = vec![id];
+        result.extend(field_to_write.to_bytes());
+        result_vec.push(result);
+    }
+
+    Ok(RecoveryOutput {
+        shares: result_vec,
+        salt: read_optional(&secret_path(committee, user_id, label, "salt.txt"))?.unwrap_or_default(),
+        skhash: read_optional(&secret_path(committee, user_id, label, "skhash.txt"))?.unwrap_or_default(),
+        pubkey: read_optional(&secret_path(committee, user_id, label, "pubkey.txt"))?,
+        ciphertext: read_optional(&secret_path(committee, user_id, label, "ciphertext.txt"))?,
+    })
+}
+
+// Runs a request and returns the result of the password check, if it made
+// one.
+fn execute(env: &dyn Env, req: &dyn Request) -> Result<Option<bool>, Box<dyn Error>> {
     let rank = env.get_world_rank();
     let num_parties = env.get_world_size();
-    let func_name = &req.func_name;
-    let args = &req.args;
+    let func_name = req.func_name();
+    let args = req.args();
 
-    println!("rank {} starting", rank);
+    let committee = load_committee()?;
+    check_args(func_name, args, committee.index_of(rank))?;
 
-    match &func_name[..] {
+    match func_name {
         "upload_sk_and_pwd" => {
-            let user_id = String::from_utf8(args[0].clone())
-                .expect("User ID is not UTF-8 encoded");
-            let sk_shares = &args[1];
-            let pwd_share = &args[2];
-            let salt = &args[3];
-            let skhash = &args[4];
-
-            fs::write(format!("{}sk.txt", &user_id), sk_shares)?;
-            fs::write(format!("{}pwd.txt", &user_id), pwd_share)?;
-            fs::write(format!("{}skhash.txt", &user_id), skhash)?;
-            fs::write(format!("{}salt.txt", &user_id), salt)?;
-
-            Ok(())
+            let sk_shares = arg(args, 1)?;
+            let pwd_share = arg(args, 2)?;
+            let meta = meta_arg(args, 5, SecretKind::Text)?;
+
+            committee.index_of(rank).ok_or(ServerError::NotMember)?;
+            let user_id = user_arg(args)?;
+            let secret = parse_secret(meta, sk_shares, arg_range(args, 3..5)?)?;
+            register_user(&committee, &user_id, pwd_share, secret)?;
+
+            Ok(None)
+        },
+        "upload_ec_key" => {
+            let sk_shares = arg(args, 1)?;
+            let pwd_share = arg(args, 2)?;
+            let meta = meta_arg(args, 4, SecretKind::EcKey)?;
+
+            committee.index_of(rank).ok_or(ServerError::NotMember)?;
+            let user_id = user_arg(args)?;
+            // only the public key is kept to verify recovery of an EC key
+            let secret = parse_secret(meta, sk_shares, arg_range(args, 3..4)?)?;
+            register_user(&committee, &user_id, pwd_share, secret)?;
+
+            Ok(None)
+        },
+        "upload_encrypted" => {
+            let key_shares = arg(args, 1)?;
+            let pwd_share = arg(args, 2)?;
+            let meta = meta_arg(args, 4, SecretKind::Encrypted)?;
+
+            committee.index_of(rank).ok_or(ServerError::NotMember)?;
+            let user_id = user_arg(args)?;
+            let secret = parse_secret(meta, key_shares, arg_range(args, 3..4)?)?;
+            register_user(&committee, &user_id, pwd_share, secret)?;
+
+            Ok(None)
         },
+        "add_secret" => {
+            let idx = committee.index_of(rank).ok_or(ServerError::NotMember)?;
+            let user_id = known_user_arg(&committee, args)?;
+            let (factor, pwd_guess_share) = guess_arg(args, 1)?;
+            let meta: SecretMeta = json_arg(args, 2)?;
+            validate_label(&meta.label).map_err(bad_arguments)?;
+            let secret = parse_secret(meta, arg(args, 3)?, arg_range(args, 4..)?)?;
+
+            let exists = |index: &[SecretMeta]| index.iter().any(|meta| meta.label == secret.meta.label);
+            if exists(&read_index(&committee, &user_id)?) {
+                return Err(ServerError::AlreadyExists(format!("secret {}", secret.meta.label)).into());
+            }
+
+            // the new secret is only accepted under the user's current password
+            let added = check_password(req, &committee, idx, &user_id, &factor, pwd_guess_share)?;
+            if added {
+                // another request may have added it during the check
+                let _user = lock_user(&user_id);
+                let mut index = read_index(&committee, &user_id)?;
+                if exists(&index) {
+                    return Err(ServerError::AlreadyExists(format!("secret {}", secret.meta.label)).into());
+                }
+                let dir = data_dir(committee.epoch);
+                write_secret_record(&dir, &user_id, &secret)?;
+                index.push(secret.meta);
+                write_index(&dir, &user_id, &index)?;
+            }
+            req.output(&serde_json::to_vec(&added)?)?;
+
+            Ok(Some(added))
+        }
+        "list_secrets" => {
+            committee.index_of(rank).ok_or(ServerError::NotMember)?;
+            let user_id = known_user_arg(&committee, args)?;
+            let _user = lock_user(&user_id);
+            req.output(&serde_json::to_vec(&read_index(&committee, &user_id)?)?)?;
+
+            Ok(None)
+        }
         "skrecovery" => {
-            let user_id = String::from_utf8(args[0].clone())
-                .expect("User ID is not UTF-8 encoded");
-
-            // compute R(PW-PWG) share locally
-            
-            let sk_shares_data = fs::read(format!("{}sk.txt", user_id))?;
-            let sk_shares: Vec<Share<33>> = serde_json::from_slice(&sk_shares_data)?;
-
-            let pwd_share_data = fs::read(format!("{}pwd.txt", user_id))?;
-            let pwd_share: Scalar = Share::<33>::try_from(pwd_share_data.as_slice())?.as_field_element().unwrap();
-            let pwd_guess_share: Scalar = Share::<33>::try_from(args[1].as_ref())?.as_field_element().unwrap();
-
-            // Thanks Emma for showing us this neat trick!
-            // https://citeseerx.ist.psu.edu/document?repid=rep1&type=pdf&doi=96317e8e38cc956da308026e5328948ebd9d49ad
-
-            let a_size = NUM_SERVERS - THRESHOLD;
-            let my_as = generate_a(num_parties, a_size, rank);
-            let r_a = (0..NUM_A).map(|i| {
-                let prg_data = fs::read(format!("{}_prg.json", i))?;
-                let prg_string = String::from_utf8(prg_data).unwrap();
-                println!("{}", prg_string);
-                let mut rng: ChaCha20Rng = serde_json::from_str(&prg_string)?;
-                let r_a = Scalar::random(rng.clone());
-                let _change_rng = rng.gen::<u64>(); // change prg state before storing again
-                let serialized_rng = serde_json::to_string(&rng)?;
-                fs::write(format!("{}_prg.json", i), serialized_rng.as_bytes())?;
-                Ok(r_a)
-            }).collect::<Result<Vec<Scalar>, IoError>>()?;
-
-            let f_a = my_as.iter().map(|a| {
-                let mut fa_j = Scalar::one();
-                let factors = n_sub_a(num_parties, a.to_vec());
-                for f in factors {
-                    fa_j *= Scalar::from_uint_reduced(U256::from(rank as u32)) - Scalar::from_uint_reduced(U256::from(f as u8));
+            let idx = committee.index_of(rank).ok_or(ServerError::NotMember)?;
+            let user_id = known_user_arg(&committee, args)?;
+            let guess = guess_arg(args, 1)?;
+            let label = label_arg(args, 2)?;
+            let pending_id = args.get(3).map(|_| string_arg(args, 3)).transpose()?;
+
+            // a pending recovery is only used up by the right password, so
+            // the committee checks the guess first, whether or not the delay
+            // has run out by now on every member
+            let correct = match pending_id {
+                Some(_) => Some(check_password(req, &committee, idx, &user_id, &guess.0, guess.1)?),
+                None => None,
+            };
+            let output = release_shares(&committee, idx, req.id(), &user_id, &label, &guess, pending_id.as_deref().zip(correct))?;
+            req.output(&output.to_bytes())?;
+
+            Ok(correct)
+        }
+        "set_guardians" => {
+            let idx = committee.index_of(rank).ok_or(ServerError::NotMember)?;
+            let user_id = known_user_arg(&committee, args)?;
+            let (factor, pwd_guess_share) = guess_arg(args, 1)?;
+            let policy: GuardianPolicy = json_arg(args, 2)?;
+            policy.validate().map_err(bad_arguments)?;
+
+            let set = check_password(req, &committee, idx, &user_id, &factor, pwd_guess_share)?;
+            if set {
+                let _user = lock_user(&user_id);
+                refuse_if_delayed(&committee, &user_id)?;
+                write_guardians(&data_dir(committee.epoch), &user_id, &Some(policy), &[])?;
+            }
+            req.output(&serde_json::to_vec(&set)?)?;
+
+            Ok(Some(set))
+        }
+        "list_guardians" => {
+            committee.index_of(rank).ok_or(ServerError::NotMember)?;
+            let user_id = known_user_arg(&committee, args)?;
+            req.output(&serde_json::to_vec(&read_guardians(&committee, &user_id)?)?)?;
+
+            Ok(None)
+        }
+        "guardian_recovery" => {
+            committee.index_of(rank).ok_or(ServerError::NotMember)?;
+            let user_id = known_user_arg(&committee, args)?;
+            let request_id = string_arg(args, 1)?;
+            validate_label(&request_id).map_err(bad_arguments)?;
+            let approvals: Vec<Vec<u8>> = json_arg(args, 2)?;
+            let label = label_arg(args, 3)?;
+            let _user = lock_user(&user_id);
+            find_secret(&committee, &user_id, &label)?;
+
+            let policy = read_guardians(&committee, &user_id)?.ok_or_else(|| ServerError::Refused("user has no guardians".to_owned()))?;
+            let mut used_approvals = read_used_approvals(&committee, &user_id)?;
+            if used_approvals.contains(&request_id) {
+                return Err(ServerError::Refused(format!("recovery request {} was already used", request_id)).into());
+            }
+            let msg = guardian_approval_message(&user_id, &label, &request_id);
+            let approved = policy.count_approvals(&msg, &approvals);
+            if approved < policy.threshold {
+                return Err(ServerError::Refused(format!("{} of {} guardian approvals", approved, policy.threshold)).into());
+            }
+            // with a recovery delay, the first approved request opens a
+            // pending recovery under its ID, which the owner can see and
+            // cancel, and the same request releases shares once it is ready
+            let mut state = read_recovery_state(&committee, &user_id)?;
+            let now = now_secs()?;
+            let delay = state.current_delay(now);
+            if delay > 0 {
+                let Some(position) = state.pending.iter().position(|pending| pending.request_id == request_id && pending.label == label) else {
+                    let ready_at = now + delay;
+                    state.pending.push(PendingRecovery { request_id: request_id.clone(), label, created: now, ready_at });
+                    write_recovery_state(&data_dir(committee.epoch), &user_id, &state)?;
+                    return Err(ServerError::Refused(format!("recovery is delayed, request {} is ready at {}", request_id, ready_at)).into());
+                };
+                if state.pending[position].ready_at > now {
+                    return Err(ServerError::Refused(format!("recovery {} is not ready until {}", request_id, state.pending[position].ready_at)).into());
                 }
-                fa_j
-            });
-            let random_hiding = f_a.zip(r_a).fold(Scalar::zero(), |prev, f_and_r| prev + f_and_r.0 * f_and_r.1); 
+                state.pending.remove(position);
+                write_recovery_state(&data_dir(committee.epoch), &user_id, &state)?;
+            }
+
+            // spend the request ID before releasing anything
+            used_approvals.push(request_id);
+            write_guardians(&data_dir(committee.epoch), &user_id, &None, &used_approvals)?;
+
+            // the approvals stand in for the password check, so the shares
+            // are released unmasked
+            let sk_shares_data = Zeroizing::new(fs::read(secret_path(&committee, &user_id, &label, "sk.txt"))?);
+            let mut sk_shares = decode_shares(&sk_shares_data)?;
+            let output = RecoveryOutput {
+                shares: Secret::new(sk_shares.iter().map(|share| share.as_ref().to_vec()).collect()),
+                salt: read_optional(&secret_path(&committee, &user_id, &label, "salt.txt"))?.unwrap_or_default(),
+                skhash: read_optional(&secret_path(&committee, &user_id, &label, "skhash.txt"))?.unwrap_or_default(),
+                pubkey: read_optional(&secret_path(&committee, &user_id, &label, "pubkey.txt"))?,
+                ciphertext: read_optional(&secret_path(&committee, &user_id, &label, "ciphertext.txt"))?,
+            };
+            sk_shares.iter_mut().for_each(|share| share.0.zeroize());
+            req.output(&output.to_bytes())?;
 
-            let mut result_vec = Vec::new();
-            for sk_share in sk_shares {
-                let id = sk_share.identifier();
-                let share: Scalar = sk_share.as_field_element().unwrap();
-                let field_to_write = (pwd_share - pwd_guess_share) * random_hiding + share;
-                let mut result = vec![id];
-                result.extend(field_to_write.to_bytes());
-                result_vec.push(result);
+            Ok(None)
+        }
+        "set_recovery_delay" => {
+            let idx = committee.index_of(rank).ok_or(ServerError::NotMember)?;
+            let user_id = known_user_arg(&committee, args)?;
+            let (factor, pwd_guess_share) = guess_arg(args, 1)?;
+            let seconds: u64 = string_arg(args, 2)?.parse().map_err(bad_arguments)?;
+
+            let mut effective_at = None;
+            if check_password(req, &committee, idx, &user_id, &factor, pwd_guess_share)? {
+                let _user = lock_user(&user_id);
+                let mut state = read_recovery_state(&committee, &user_id)?;
+                effective_at = Some(state.set_delay(seconds, now_secs()?));
+                write_recovery_state(&data_dir(committee.epoch), &user_id, &state)?;
             }
+            req.output(&serde_json::to_vec(&effective_at)?)?;
 
-            let salt = fs::read(format!("{}salt.txt", user_id))?;
-            let skhash = fs::read(format!("{}skhash.txt", user_id))?;
+            Ok(Some(effective_at.is_some()))
+        }
+        "request_recovery" => {
+            let idx = committee.index_of(rank).ok_or(ServerError::NotMember)?;
+            let user_id = known_user_arg(&committee, args)?;
+            let (factor, pwd_guess_share) = guess_arg(args, 1)?;
+            let request_id = string_arg(args, 2)?;
+            validate_label(&request_id).map_err(bad_arguments)?;
+            let label = label_arg(args, 3)?;
+            find_secret(&committee, &user_id, &label)?;
+            let exists = |state: &RecoveryState| state.pending.iter().any(|pending| pending.request_id == request_id);
+            if exists(&read_recovery_state(&committee, &user_id)?) {
+                return Err(ServerError::AlreadyExists(format!("recovery {}", request_id)).into());
+            }
 
-            let result_vec_to_output = serde_json::to_vec(&(result_vec, salt, skhash)).unwrap();
-            req.output(&result_vec_to_output)?;
+            let mut opened = None;
+            if check_password(req, &committee, idx, &user_id, &factor, pwd_guess_share)? {
+                let _user = lock_user(&user_id);
+                let mut state = read_recovery_state(&committee, &user_id)?;
+                if exists(&state) {
+                    return Err(ServerError::AlreadyExists(format!("recovery {}", request_id)).into());
+                }
+                let now = now_secs()?;
+                let pending = PendingRecovery {
+                    request_id,
+                    label,
+                    created: now,
+                    ready_at: now + state.current_delay(now),
+                };
+                state.pending.push(pending.clone());
+                write_recovery_state(&data_dir(committee.epoch), &user_id, &state)?;
+                opened = Some(pending);
+            }
+            req.output(&serde_json::to_vec(&opened)?)?;
 
-            Ok(())
+            Ok(Some(opened.is_some()))
+        }
+        "cancel_recovery" => {
+            let idx = committee.index_of(rank).ok_or(ServerError::NotMember)?;
+            let user_id = known_user_arg(&committee, args)?;
+            let (factor, pwd_guess_share) = guess_arg(args, 1)?;
+            // without a request ID every pending recovery is cancelled
+            let request_id = args.get(2).map(|_| string_arg(args, 2)).transpose()?;
+
+            let cancelled = check_password(req, &committee, idx, &user_id, &factor, pwd_guess_share)?;
+            if cancelled {
+                let _user = lock_user(&user_id);
+                let mut state = read_recovery_state(&committee, &user_id)?;
+                state.pending.retain(|pending| request_id.as_ref().is_some_and(|id| *id != pending.request_id));
+                if request_id.is_none() {
+                    state.lowering = None;
+                }
+                write_recovery_state(&data_dir(committee.epoch), &user_id, &state)?;
+            }
+            req.output(&serde_json::to_vec(&cancelled)?)?;
+
+            Ok(Some(cancelled))
+        }
+        "recovery_status" => {
+            committee.index_of(rank).ok_or(ServerError::NotMember)?;
+            let user_id = known_user_arg(&committee, args)?;
+            req.output(&serde_json::to_vec(&read_recovery_state(&committee, &user_id)?)?)?;
+
+            Ok(None)
+        }
+        "sign" => {
+            let idx = committee.index_of(rank).ok_or(ServerError::NotMember)?;
+            let user_id = known_user_arg(&committee, args)?;
+            let (factor, pwd_guess_share) = guess_arg(args, 1)?;
+            let msg = arg(args, 2)?;
+            let label = label_arg(args, 3)?;
+
+            let ((sk_share, pubkey), pwd_share) = {
+                let _user = lock_user(&user_id);
+                refuse_if_delayed(&committee, &user_id)?;
+                (read_ec_key_share(&committee, &user_id, &label)?, read_pwd_share(&committee, &user_id, &factor)?)
+            };
+
+            // a fresh shared nonce, and R to hide the response unless the
+            // password guess is correct
+            let k_share = random_hiding(&committee, idx, req.id(), NONCE)?;
+            let hiding = random_hiding(&committee, idx, req.id(), HIDING)?;
+            let nonce = encode_point(&open_nonce(req, &committee, idx, *k_share)?);
+            let e = schnorr_challenge(&nonce, &pubkey, msg);
+            let response = Zeroizing::new(*k_share + e * *sk_share + (*pwd_share - pwd_guess_share) * *hiding);
+
+            let output = SignatureShare {
+                nonce,
+                pubkey,
+                share: scalar_to_share((idx + 1) as u8, &response).as_ref().to_vec(),
+            };
+            req.output(&serde_json::to_vec(&output)?)?;
+
+            Ok(None)
+        }
+        "decrypt" => {
+            let idx = committee.index_of(rank).ok_or(ServerError::NotMember)?;
+            let user_id = known_user_arg(&committee, args)?;
+            let (factor, pwd_guess_share) = guess_arg(args, 1)?;
+            let ephemeral = decode_point(arg(args, 2)?).map_err(bad_arguments)?;
+            let label = label_arg(args, 3)?;
+
+            let _user = lock_user(&user_id);
+            refuse_if_delayed(&committee, &user_id)?;
+            let (sk_share, _) = read_ec_key_share(&committee, &user_id, &label)?;
+            let pwd_share = read_pwd_share(&committee, &user_id, &factor)?;
+
+            // same masking as skrecovery, applied in the exponent
+            let hiding = random_hiding(&committee, idx, req.id(), HIDING)?;
+            let output = DecryptionShare {
+                id: (idx + 1) as u8,
+                point: encode_point(&(ephemeral * (*sk_share + (*pwd_share - pwd_guess_share) * *hiding))),
+            };
+            req.output(&serde_json::to_vec(&output)?)?;
+
+            Ok(None)
+        }
+        "delete_user" => {
+            let idx = committee.index_of(rank).ok_or(ServerError::NotMember)?;
+            let user_id = known_user_arg(&committee, args)?;
+            let (factor, pwd_guess_share) = guess_arg(args, 1)?;
+
+            let deleted = check_password(req, &committee, idx, &user_id, &factor, pwd_guess_share)?;
+            if deleted {
+                // as on registration, used guardian request IDs stay spent
+                let _user = lock_user(&user_id);
+                refuse_if_delayed(&committee, &user_id)?;
+                let used_approvals = read_used_approvals(&committee, &user_id)?;
+                remove_user(&committee, &user_id)?;
+                write_guardians(&data_dir(committee.epoch), &user_id, &None, &used_approvals)?;
+            }
+            req.output(&serde_json::to_vec(&deleted)?)?;
+
+            Ok(Some(deleted))
+        }
+        "change_password" => {
+            let idx = committee.index_of(rank).ok_or(ServerError::NotMember)?;
+            let user_id = known_user_arg(&committee, args)?;
+            let (factor, pwd_guess_share) = guess_arg(args, 1)?;
+            let new_pwd_share = share_arg(args, 2)?;
+
+            let correct = check_password(req, &committee, idx, &user_id, &factor, pwd_guess_share)?;
+            if correct {
+                // the factor may have been revoked during the check
+                let _user = lock_user(&user_id);
+                refuse_if_delayed(&committee, &user_id)?;
+                if !read_factors(&committee, &user_id)?.iter().any(|meta| meta.id == factor) {
+                    return Err(ServerError::UnknownFactor(factor).into());
+                }
+                write_atomic(&factor_path(&committee, &user_id, &factor), new_pwd_share.as_ref())?;
+            }
+            req.output(&serde_json::to_vec(&correct)?)?;
+
+            Ok(Some(correct))
+        }
+        "add_factor" => {
+            let idx = committee.index_of(rank).ok_or(ServerError::NotMember)?;
+            let user_id = known_user_arg(&committee, args)?;
+            let (factor, pwd_guess_share) = guess_arg(args, 1)?;
+            let meta: FactorMeta = json_arg(args, 2)?;
+            validate_label(&meta.id).map_err(bad_arguments)?;
+            let share = share_arg(args, 3)?;
+            let exists = |factors: &[FactorMeta]| factors.iter().any(|existing| existing.id == meta.id);
+            if exists(&read_factors(&committee, &user_id)?) {
+                return Err(ServerError::AlreadyExists(format!("recovery factor {}", meta.id)).into());
+            }
+
+            let added = check_password(req, &committee, idx, &user_id, &factor, pwd_guess_share)?;
+            if added {
+                let _user = lock_user(&user_id);
+                refuse_if_delayed(&committee, &user_id)?;
+                let mut factors = read_factors(&committee, &user_id)?;
+                if exists(&factors) {
+                    return Err(ServerError::AlreadyExists(format!("recovery factor {}", meta.id)).into());
+                }
+                let dir = data_dir(committee.epoch);
+                write_factor(&dir, &user_id, &FactorRecord { meta: meta.clone(), share })?;
+                factors.push(meta);
+                write_factors(&dir, &user_id, &factors)?;
+            }
+            req.output(&serde_json::to_vec(&added)?)?;
+
+            Ok(Some(added))
+        }
+        "revoke_factor" => {
+            let idx = committee.index_of(rank).ok_or(ServerError::NotMember)?;
+            let user_id = known_user_arg(&committee, args)?;
+            let (factor, pwd_guess_share) = guess_arg(args, 1)?;
+            let revoked_id = string_arg(args, 2)?;
+            let position_of = |factors: &[FactorMeta]| {
+                let position = factors.iter().position(|existing| existing.id == revoked_id)
+                    .ok_or_else(|| ServerError::UnknownFactor(revoked_id.clone()))?;
+                if factors.len() == 1 {
+                    return Err(ServerError::Refused("cannot revoke the only recovery factor".to_owned()));
+                }
+                Ok(position)
+            };
+            position_of(&read_factors(&committee, &user_id)?)?;
+
+            let revoked = check_password(req, &committee, idx, &user_id, &factor, pwd_guess_share)?;
+            if revoked {
+                let _user = lock_user(&user_id);
+                refuse_if_delayed(&committee, &user_id)?;
+                let mut factors = read_factors(&committee, &user_id)?;
+                let position = position_of(&factors)?;
+                // drop the factor from the index first so a crash leaves at
+                // worst an unreferenced share file
+                factors.remove(position);
+                write_factors(&data_dir(committee.epoch), &user_id, &factors)?;
+                remove_if_exists(&factor_path(&committee, &user_id, &revoked_id))?;
+            }
+            req.output(&serde_json::to_vec(&revoked)?)?;
+
+            Ok(Some(revoked))
+        }
+        "list_factors" => {
+            committee.index_of(rank).ok_or(ServerError::NotMember)?;
+            let user_id = known_user_arg(&committee, args)?;
+            req.output(&serde_json::to_vec(&read_factors(&committee, &user_id)?)?)?;
+
+            Ok(None)
+        }
+        "rotate_sk" => {
+            let idx = committee.index_of(rank).ok_or(ServerError::NotMember)?;
+            let user_id = known_user_arg(&committee, args)?;
+            let (factor, pwd_guess_share) = guess_arg(args, 1)?;
+            let meta = meta_arg(args, 5, SecretKind::Text)?;
+            let mut secret = parse_secret(meta, arg(args, 2)?, arg_range(args, 3..5)?)?;
+            let label = secret.meta.label.clone();
+            let position_of = |index: &[SecretMeta]| index.iter().position(|meta| meta.label == label)
+                .ok_or_else(|| ServerError::UnknownSecret(label.clone()));
+            position_of(&read_index(&committee, &user_id)?)?;
+
+            let mut rotated = None;
+            if check_password(req, &committee, idx, &user_id, &factor, pwd_guess_share)? {
+                let _user = lock_user(&user_id);
+                refuse_if_delayed(&committee, &user_id)?;
+                let mut index = read_index(&committee, &user_id)?;
+                let position = position_of(&index)?;
+                secret.version = read_version(&committee, &user_id, &label)? + 1;
+                remove_secret_files(&committee, &user_id, &label)?;
+                let dir = data_dir(committee.epoch);
+                write_secret_record(&dir, &user_id, &secret)?;
+                rotated = Some(secret.version);
+                index[position] = secret.meta;
+                write_index(&dir, &user_id, &index)?;
+            }
+            req.output(&serde_json::to_vec(&rotated)?)?;
+
+            Ok(Some(rotated.is_some()))
         }
         "seed_prgs" => {
-            let a_size = NUM_SERVERS - THRESHOLD;
-
-            for (i, v) in generate_a(num_parties, a_size, rank as usize).iter().enumerate() {
-                let sender = 0; // I think this also works bc the set elements are all in increasing order 
-                println!("{} {:?}, {}", rank, v, sender);
-                let rng = &mut ChaCha20Rng::from_entropy();
-                let my_prg_seed = rng.gen::<u64>(); // change later?
-
-                println!("{}", my_prg_seed.to_le_bytes().len());
-
-                let prg_seed: u64;
-                if v[sender] == rank as usize {
-                    for j in 0..a_size {
-                        if v[j] != rank as usize {
-                            req.msg_send(&my_prg_seed.to_le_bytes(), v[j], 0)?;
-                        }
+            // nodes outside the committee hold no seeds
+            let idx = match committee.index_of(rank) {
+                Some(idx) => idx,
+                None => return Ok(None),
+            };
+
+            for (i, rng) in generate_prgs(req, &committee, idx)?.iter().enumerate() {
+                // Only the seed is used, but older nodes stored and advanced
+                // the whole state, so the format stays.
+                let serialized_rng = Zeroizing::new(serde_json::to_string(rng)?);
+                write_atomic(&data_path(&committee, &format!("{}_prg.json", i)), serialized_rng.as_bytes())?;
+            }
+
+            Ok(None)
+        }
+        "committee_status" => {
+            let users = if committee.index_of(rank).is_some() {
+                list_users(&committee)?
+            } else {
+                Vec::new()
+            };
+            req.output(&serde_json::to_vec(&CommitteeStatus { committee, users, schema_version: REQUEST_SCHEMA_VERSION })?)?;
+
+            Ok(None)
+        }
+        "reshare" => {
+            let new: Committee = json_arg(args, 0)?;
+            new.validate(num_parties).map_err(bad_arguments)?;
+            if new.epoch != committee.epoch + 1 {
+                return Err(bad_arguments(format!("expected epoch {}, got {}", committee.epoch + 1, new.epoch)).into());
+            }
+            let old_idx = committee.index_of(rank);
+            let new_idx = new.index_of(rank);
+
+            // Old members deal sub-shares of every record to the new members.
+            let old_users = match old_idx {
+                Some(_) => list_users(&committee)?,
+                None => Vec::new(),
+            };
+            let mut own_bundle = None;
+            if old_idx.is_some() {
+                info!(epoch = new.epoch, users = old_users.len(), "dealing records to the new committee");
+                let records = old_users.iter()
+                    .map(|user_id| {
+                        let _user = lock_user(user_id);
+                        read_user_record(&committee, user_id)
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                for (j, bundle) in deal_records(&records, &new)?.into_iter().enumerate() {
+                    if new.members[j] == rank {
+                        own_bundle = Some(bundle);
+                    } else {
+                        send_bytes(req, &serde_json::to_vec(&bundle)?, new.members[j], RESHARE_TAG)?;
                     }
-                    prg_seed = my_prg_seed;
-                } else {
-                    let mut buf = [0u8; 8];
-                    req.msg_recv(&mut buf, v[sender], 0)?;
-                    prg_seed = u64::from_le_bytes(buf);
                 }
-                // Store rng state instead of seed in file, which updates after each recovery attempt
-                let mut rng = ChaCha20Rng::seed_from_u64(prg_seed);
-                let serialized_rng = serde_json::to_string(&rng)?;
-                fs::write(format!("{}_prg.json", i), serialized_rng.as_bytes())?;
             }
 
-            Ok(())
+            // New members combine what they were dealt and agree on fresh
+            // seeds. Nothing is written until all of it has succeeded.
+            let mut staged = None;
+            if let Some(idx) = new_idx {
+                let mut bundles = Vec::new();
+                for &sender in &committee.members {
+                    let bundle: Vec<UserRecord> = if sender == rank {
+                        own_bundle.take().ok_or_else(|| ServerError::Internal("missing own bundle".to_owned()))?
+                    } else {
+                        serde_json::from_slice(&recv_bytes(req, sender, RESHARE_TAG)?)?
+                    };
+                    bundles.push(bundle);
+                }
+                let records = combine_records(bundles, &committee, idx)?;
+                info!(epoch = new.epoch, users = records.len(), "combined records from the old committee");
+                let prgs = generate_prgs(req, &new, idx)?;
+                staged = Some((records, prgs));
+            }
+
+            if let Some((records, prgs)) = staged {
+                // clear out anything left by an earlier attempt at this epoch
+                let new_dir = data_dir(new.epoch);
+                if new_dir.exists() {
+                    fs::remove_dir_all(&new_dir)?;
+                }
+                fs::create_dir_all(&new_dir)?;
+                for record in &records {
+                    write_user_record(&new_dir, record)?;
+                }
+                for (i, rng) in prgs.iter().enumerate() {
+                    fs::write(new_dir.join(format!("{}_prg.json", i)), serde_json::to_string(rng)?.as_bytes())?;
+                }
+            }
+            // Replacing the committee file switches this node to the new epoch.
+            write_atomic(&node_path(COMMITTEE_FILE), &serde_json::to_vec(&new)?)?;
+
+            if old_idx.is_some() {
+                remove_epoch_data(&committee, &old_users)?;
+            }
+
+            Ok(None)
+        }
+        "audit_log" => {
+            req.output(&read_optional(&node_path(AUDIT_FILE))?.unwrap_or_default())?;
+
+            Ok(None)
+        }
+        _ => Err(ServerError::UnknownFunction(func_name.to_owned()).into()),
+    }
+}
+
+// Errors that were not given a kind where they happened are put down to
+// storage if they come from the file system.
+fn server_error(e: Box<dyn Error>) -> ServerError {
+    match e.downcast::<ServerError>() {
+        Ok(e) => *e,
+        Err(e) => match e.downcast::<IoError>() {
+            Ok(e) => ServerError::Storage(e.to_string()),
+            Err(e) => ServerError::Internal(e.to_string()),
+        },
+    }
+}
+
+// Runs a request, returning any error to the client in place of the output.
+fn handle_request(env: &dyn Env, req: &dyn Request) -> Result<(), Box<dyn Error>> {
+    let span = info_span!("request", request_id = %req.id(), func = %req.func_name(), rank = env.get_world_rank());
+    let _enter = span.enter();
+    info!("starting");
+    let result = execute(env, req).map_err(server_error);
+    match &result {
+        Ok(None) => info!("finished"),
+        Ok(Some(correct)) => info!(correct, "finished after password check"),
+        Err(e) => error!(error = %e, "failed"),
+    }
+    if let Err(e) = &result {
+        req.output(&e.to_output())?;
+    }
+    if !UNAUDITED.contains(&req.func_name()) {
+        let outcome = match &result {
+            Ok(None) => AuditOutcome::Ok,
+            Ok(Some(true)) => AuditOutcome::PasswordCorrect,
+            Ok(Some(false)) => AuditOutcome::PasswordIncorrect,
+            Err(e) => AuditOutcome::Error(e.to_string()),
+        };
+        append_audit(req, outcome)?;
+    }
+    Ok(())
+}
+
+// Refuses a request accepted after shutdown began.
+fn refuse(req: libdots::request::Request) {
+    warn!(request_id = %req.id, func = %req.func_name, "refused, shutting down");
+    let e = ServerError::Refused("node is shutting down".to_owned());
+    if let Err(e) = req.output(&e.to_output()) {
+        error!(request_id = %req.id, error = %e, "could not refuse request");
+    }
+}
+
+// Hands accepted requests to the workers until the queue is closed, refusing
+// them after that. Sending blocks while the queue is full, so a burst of
+// requests waits in dots rather than here.
+fn accept_requests(queue: &Mutex<Option<SyncSender<libdots::request::Request>>>, stop: Sender<String>) {
+    loop {
+        let req = match libdots::request::accept() {
+            Ok(req) => req,
+            Err(e) => {
+                error!(error = %e, "could not accept request");
+                let _ = stop.send("accept failed".to_owned());
+                return;
+            }
+        };
+        // send on a clone, so that closing the queue does not wait for room
+        let tx = queue.lock().unwrap_or_else(PoisonError::into_inner).clone();
+        match tx {
+            Some(tx) => {
+                if let Err(SendError(req)) = tx.send(req) {
+                    refuse(req);
+                }
+            }
+            None => refuse(req),
+        }
+    }
+}
+
+// Limits how many interactive requests run at once. Taking a slot blocks
+// until one is free.
+struct Slots {
+    running: Mutex<usize>,
+    freed: Condvar,
+    max: usize,
+}
+
+struct Slot<'a>(&'a Slots);
+
+impl Slots {
+    fn new(max: usize) -> Self {
+        Slots { running: Mutex::new(0), freed: Condvar::new(), max }
+    }
+
+    fn take(&self) -> Slot<'_> {
+        let running = self.running.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut running = self.freed.wait_while(running, |running| *running >= self.max).unwrap_or_else(PoisonError::into_inner);
+        *running += 1;
+        Slot(self)
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        *self.0.running.lock().unwrap_or_else(PoisonError::into_inner) -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+#[test]
+fn test_slots() {
+    let slots = Slots::new(2);
+    let first = slots.take();
+    let _second = slots.take();
+    thread::scope(|s| {
+        let third = s.spawn(|| {
+            slots.take();
+        });
+        thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!third.is_finished());
+        drop(first);
+        third.join().unwrap();
+    });
+}
+
+// Handles queued requests until the queue is closed and empty. Interactive
+// requests are handed to threads of their own, so that the workers never wait
+// on other members, as long as there are slots for them.
+fn work<'scope, 'env>(s: &'scope thread::Scope<'scope, 'env>, env: &'env libdots::env::Env, queue: &Mutex<Receiver<libdots::request::Request>>, slots: &'env Slots) {
+    loop {
+        let req = match queue.lock().unwrap_or_else(PoisonError::into_inner).recv() {
+            Ok(req) => req,
+            Err(_) => return,
+        };
+        if INTERACTIVE.contains(&req.func_name.as_str()) {
+            let slot = slots.take();
+            s.spawn(move || {
+                complete(env, &req);
+                drop(slot);
+            });
+        } else {
+            complete(env, &req);
         }
-        _ => panic!(),
     }
 }
 
+fn complete(env: &libdots::env::Env, req: &libdots::request::Request) {
+    // whatever fails here can no longer reach the client
+    if let Err(e) = handle_request(env, req) {
+        error!(request_id = %req.id, error = %e, "could not complete request");
+    }
+}
+
+// Reads a limit from the environment, falling back to `default` if unset.
+fn limit(name: &str, value: Option<String>, default: usize, min: usize) -> Result<usize, Box<dyn Error>> {
+    let Some(value) = value else {
+        return Ok(default);
+    };
+    match value.parse() {
+        Ok(n) if n >= min => Ok(n),
+        _ => Err(format!("{} must be a number of at least {}, got {:?}", name, min, value).into()),
+    }
+}
+
+#[test]
+fn test_limit() {
+    assert_eq!(limit("N", None, 16, 1).unwrap(), 16);
+    assert_eq!(limit("N", Some("4".to_owned()), 16, 1).unwrap(), 4);
+    assert_eq!(limit("N", Some("0".to_owned()), 16, 0).unwrap(), 0);
+    assert!(limit("N", Some("0".to_owned()), 16, 1).is_err());
+    assert!(limit("N", Some("-1".to_owned()), 16, 0).is_err());
+    assert!(limit("N", Some("many".to_owned()), 16, 0).is_err());
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
+    init_tracing("info");
+    let workers = limit(WORKERS_VAR, std::env::var(WORKERS_VAR).ok(), DEFAULT_WORKERS, 1)?;
+    let queue_len = limit(QUEUE_VAR, std::env::var(QUEUE_VAR).ok(), DEFAULT_QUEUE_LEN, 0)?;
     let env = libdots::env::init()?;
 
-    thread::scope(|s| -> Result<(), Box<dyn Error>> {
-        loop {
-            let env = &env;
-            let req = libdots::request::accept()?;
-            s.spawn(move || {
-                handle_request(env, &req).unwrap();
-            });
+    let (stop_tx, stop_rx) = mpsc::channel();
+    let mut signals = Signals::new([SIGTERM, SIGINT])?;
+    let signal_stop = stop_tx.clone();
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            let _ = signal_stop.send(format!("signal {}", signal));
+        }
+    });
+
+    let (tx, rx) = mpsc::sync_channel(queue_len);
+    let queue = Arc::new(Mutex::new(Some(tx)));
+    let rx = Mutex::new(rx);
+    let slots = Slots::new(workers * INTERACTIVE_PER_WORKER);
+    // Not scoped: it may be blocked in `accept` when the process exits.
+    let accept_queue = queue.clone();
+    thread::spawn(move || accept_requests(&accept_queue, stop_tx));
+    info!(workers, queue_len, "accepting requests");
+
+    thread::scope(|s| {
+        for _ in 0..workers {
+            s.spawn(|| work(s, &env, &rx, &slots));
         }
-    })?;
+        let reason = stop_rx.recv().unwrap_or_else(|_| "no way left to stop".to_owned());
+        info!(%reason, "shutting down, finishing accepted requests");
+        // Dropping the sender lets the workers drain the queue and return,
+        // after any request that accept_requests is still sending.
+        queue.lock().unwrap_or_else(PoisonError::into_inner).take();
+    });
+    info!("stopped");
 
     Ok(())
 }
@@ -0,0 +1,75 @@
+// Copyright 2023 The Dots Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// What the handlers use of dots, so that they can run on simulated nodes as
+// well as under a dots server.
+
+use std::error::Error;
+
+use uuid::Uuid;
+
+/// The node a request runs on.
+pub trait Env: Sync {
+    fn get_world_rank(&self) -> usize;
+    fn get_world_size(&self) -> usize;
+}
+
+/// A request as one node sees it. Messages go to and come from the other
+/// nodes running the same request.
+pub trait Request: Sync {
+    fn id(&self) -> Uuid;
+    fn func_name(&self) -> &str;
+    fn args(&self) -> &[Vec<u8>];
+    fn output(&self, data: &[u8]) -> Result<(), Box<dyn Error>>;
+    fn msg_send(&self, buf: &[u8], recipient: usize, tag: i32) -> Result<(), Box<dyn Error>>;
+    /// Fills `buf` from the next message of `sender` with this tag and returns
+    /// its length.
+    fn msg_recv(&self, buf: &mut [u8], sender: usize, tag: i32) -> Result<usize, Box<dyn Error>>;
+}
+
+impl Env for libdots::env::Env {
+    fn get_world_rank(&self) -> usize {
+        libdots::env::Env::get_world_rank(self)
+    }
+
+    fn get_world_size(&self) -> usize {
+        libdots::env::Env::get_world_size(self)
+    }
+}
+
+impl Request for libdots::request::Request {
+    fn id(&self) -> Uuid {
+        self.id
+    }
+
+    fn func_name(&self) -> &str {
+        &self.func_name
+    }
+
+    fn args(&self) -> &[Vec<u8>] {
+        &self.args
+    }
+
+    fn output(&self, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        Ok(libdots::request::Request::output(self, data)?)
+    }
+
+    fn msg_send(&self, buf: &[u8], recipient: usize, tag: i32) -> Result<(), Box<dyn Error>> {
+        Ok(libdots::request::Request::msg_send(self, buf, recipient, tag)?)
+    }
+
+    fn msg_recv(&self, buf: &mut [u8], sender: usize, tag: i32) -> Result<usize, Box<dyn Error>> {
+        Ok(libdots::request::Request::msg_recv(self, buf, sender, tag)?)
+    }
+}
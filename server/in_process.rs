@@ -0,0 +1,226 @@
+// Copyright 2023 The Dots Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The client library run against the handlers on simulated nodes, for tests
+// that cover both ends of a request.
+
+use std::sync::{Mutex, PoisonError};
+
+use elliptic_curve::Field;
+use skrecovery::transport::{Outputs, Recorded, Transport};
+use skrecovery::util::{self, GuardianPolicy, RecoveryState, DEFAULT_FACTOR};
+use skrecovery::{ClientError, SecretInput, SkRecoveryClient};
+use uuid::Uuid;
+
+use super::sim::Cluster;
+
+/// Rewrites a node's output, given the function it ran.
+type Tamper = fn(&str, Vec<u8>) -> Vec<u8>;
+
+/// Server handlers on nodes simulated in this process. Nodes taken down
+/// cannot be reached, and a tamper applies to the outputs of one node.
+struct InProcess {
+    cluster: Cluster,
+    down: Mutex<Vec<usize>>,
+    tamper: Mutex<Option<(usize, Tamper)>>,
+}
+
+impl InProcess {
+    fn new(num_nodes: usize) -> InProcess {
+        InProcess { cluster: Cluster::new(num_nodes), down: Mutex::new(vec![]), tamper: Mutex::new(None) }
+    }
+
+    fn take_down(&self, ranks: Vec<usize>) {
+        *self.down.lock().unwrap_or_else(PoisonError::into_inner) = ranks;
+    }
+
+    fn tamper_with(&self, rank: usize, tamper: Tamper) {
+        *self.tamper.lock().unwrap_or_else(PoisonError::into_inner) = Some((rank, tamper));
+    }
+}
+
+impl Transport for InProcess {
+    fn num_nodes(&self) -> usize {
+        self.cluster.size
+    }
+
+    fn exec<'a>(&'a self, request_id: Uuid, func_name: &'a str, node_args: Vec<(usize, Vec<Vec<u8>>)>) -> Outputs<'a> {
+        Box::pin(async move {
+            let down = self.down.lock().unwrap_or_else(PoisonError::into_inner).clone();
+            if let Some((rank, _)) = node_args.iter().find(|(rank, _)| down.contains(rank) || *rank >= self.num_nodes()) {
+                return Err(ClientError::Transport(format!("node {} is unreachable", rank)));
+            }
+            let ranks: Vec<usize> = node_args.iter().map(|(rank, _)| *rank).collect();
+            let outputs = self.cluster.run_request(request_id, func_name, node_args);
+            let tamper = *self.tamper.lock().unwrap_or_else(PoisonError::into_inner);
+            Ok(ranks
+                .into_iter()
+                .zip(outputs)
+                .map(|(rank, output)| match tamper {
+                    Some((tampered, tamper)) if tampered == rank => tamper(func_name, output),
+                    _ => output,
+                })
+                .collect())
+        })
+    }
+}
+
+async fn registered_client(id: &str, sk: &str, pwd: &str) -> SkRecoveryClient<InProcess> {
+    let client = SkRecoveryClient::new(InProcess::new(util::NUM_SERVERS));
+    client.seed_prgs().await.unwrap();
+    client.register(id, util::DEFAULT_LABEL, &SecretInput::Text(sk.to_owned()), pwd).await.unwrap();
+    client
+}
+
+#[tokio::test]
+async fn test_recover_in_process() {
+    let client = registered_client("alice", "correct horse battery staple", "hunter2").await;
+
+    let s = client.recover("alice", util::DEFAULT_LABEL, "hunter2").await.unwrap();
+    assert_eq!(&*s, b"correct horse battery staple");
+    assert!(matches!(client.recover("alice", util::DEFAULT_LABEL, "hunter3").await, Err(ClientError::WrongPassword)));
+
+    // an unreachable node fails the request rather than the aggregation
+    client.transport().take_down(vec![3]);
+    let e = client.recover("alice", util::DEFAULT_LABEL, "hunter2").await.unwrap_err();
+    assert!(matches!(&e, ClientError::Transport(_)));
+    assert_eq!(e.to_string(), "node 3 is unreachable");
+}
+
+#[tokio::test]
+async fn test_node_errors_and_disagreement() {
+    let client = registered_client("alice", "correct horse battery staple", "hunter2").await;
+
+    let Err(ClientError::Nodes(errors)) = client.recover("bob", util::DEFAULT_LABEL, "hunter2").await else {
+        panic!("expected errors from the nodes");
+    };
+    assert_eq!(errors.0, (0..util::NUM_SERVERS).map(|rank| (rank, util::ServerError::UnknownUser("bob".to_owned()))).collect::<Vec<_>>());
+
+    // one node returning a different hash is singled out
+    client.transport().tamper_with(2, |func_name, output| {
+        if func_name != "skrecovery" {
+            return output;
+        }
+        let mut output = util::RecoveryOutput::from_bytes(&output).unwrap();
+        output.skhash[0] ^= 1;
+        output.to_bytes().to_vec()
+    });
+    let e = client.recover("alice", util::DEFAULT_LABEL, "hunter2").await.unwrap_err();
+    assert!(matches!(&e, ClientError::Inconsistent(ranks) if *ranks == [2]));
+    assert_eq!(e.to_string(), "nodes at ranks [2] reported different verification data");
+
+    // a node missing a chunk of the key fails the recovery rather than the client
+    client.transport().tamper_with(1, |func_name, output| {
+        if func_name != "skrecovery" {
+            return output;
+        }
+        let mut output = util::RecoveryOutput::from_bytes(&output).unwrap();
+        output.shares.pop();
+        output.to_bytes().to_vec()
+    });
+    let e = client.recover("alice", util::DEFAULT_LABEL, "hunter2").await.unwrap_err();
+    assert_eq!(e.to_string(), "nodes returned different numbers of key shares");
+
+    client.transport().tamper_with(4, |func_name, output| match func_name {
+        "list_secrets" => b"[]".to_vec(),
+        _ => output,
+    });
+    let e = client.list_secrets("alice").await.unwrap_err();
+    assert!(matches!(&e, ClientError::Disagreement(_)));
+    assert_eq!(e.to_string(), "nodes disagree on the user's secrets");
+}
+
+#[tokio::test]
+async fn test_change_password_status_and_delete() {
+    let client = registered_client("alice", "correct horse battery staple", "hunter2").await;
+
+    assert!(matches!(client.change_password("alice", "hunter3", "swordfish").await, Err(ClientError::WrongPassword)));
+    client.change_password("alice", "hunter2", "swordfish").await.unwrap();
+    let s = client.recover("alice", util::DEFAULT_LABEL, "swordfish").await.unwrap();
+    assert_eq!(&*s, b"correct horse battery staple");
+
+    let status = client.status("alice").await.unwrap();
+    assert_eq!(status.secrets.iter().map(|meta| &meta.label[..]).collect::<Vec<_>>(), [util::DEFAULT_LABEL]);
+    assert_eq!(status.factors.iter().map(|meta| &meta.id[..]).collect::<Vec<_>>(), [DEFAULT_FACTOR]);
+    assert_eq!(status.recovery, RecoveryState::default());
+
+    assert!(matches!(client.delete("alice", "hunter2").await, Err(ClientError::WrongPassword)));
+    client.delete("alice", "swordfish").await.unwrap();
+    assert!(matches!(client.status("alice").await, Err(ClientError::Nodes(_))));
+    assert!(client.node_statuses().await.unwrap().iter().all(|status| status.users.is_empty()));
+}
+
+#[tokio::test]
+async fn test_recovery_delay_holds_changes() {
+    let client = registered_client("alice", "correct horse battery staple", "hunter2").await;
+    let rng = &mut rand::thread_rng();
+    let guardian = p256::Scalar::random(&mut *rng);
+    let policy = GuardianPolicy { threshold: 1, guardians: vec![hex::encode(util::ec_public_key(&guardian))] };
+    client.set_guardians("alice", &policy, "hunter2").await.unwrap();
+    client.set_recovery_delay("alice", 3600, "hunter2").await.unwrap();
+
+    // the password alone no longer changes how the secret can be recovered
+    let refused = |result: Result<(), ClientError>| matches!(result, Err(ClientError::Nodes(errors)) if errors.0.iter().all(|(_, e)| matches!(e, util::ServerError::Refused(_))));
+    assert!(refused(client.set_guardians("alice", &policy, "hunter2").await));
+    assert!(refused(client.change_password("alice", "hunter2", "swordfish").await));
+    assert!(refused(client.delete("alice", "hunter2").await));
+    assert!(refused(client.sign("alice", util::DEFAULT_LABEL, b"hello", "hunter2").await.map(|_| ())));
+
+    // guardian approvals open a pending recovery rather than releasing shares
+    let approval = util::schnorr_sign(&guardian, &util::guardian_approval_message("alice", util::DEFAULT_LABEL, "lost-phone"), rng);
+    assert!(matches!(client.recover_with_guardians("alice", util::DEFAULT_LABEL, "lost-phone", &[approval]).await, Err(ClientError::Nodes(_))));
+    let state = client.recovery_status("alice").await.unwrap();
+    assert_eq!(state.pending.iter().map(|pending| &pending.request_id[..]).collect::<Vec<_>>(), ["lost-phone"]);
+
+    // cancelling that one recovery leaves a pending lowering of the delay
+    client.set_recovery_delay("alice", 0, "hunter2").await.unwrap();
+    client.cancel_recovery("alice", Some("lost-phone"), "hunter2").await.unwrap();
+    let state = client.recovery_status("alice").await.unwrap();
+    assert!(state.pending.is_empty());
+    assert!(state.lowering.is_some());
+    client.cancel_recovery("alice", None, "hunter2").await.unwrap();
+    assert_eq!(client.recovery_status("alice").await.unwrap().lowering, None);
+}
+
+#[tokio::test]
+async fn test_pending_recovery_needs_the_password() {
+    let client = registered_client("carol", "correct horse battery staple", "hunter2").await;
+    client.set_recovery_delay("carol", 1, "hunter2").await.unwrap();
+    let pending = client.request_recovery("carol", util::DEFAULT_LABEL, "hunter2").await.unwrap();
+    let request_id = pending.request_id.clone();
+    tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+
+    // a wrong guess gets nothing and leaves the recovery pending
+    let wrong = client.complete_recovery("carol", util::DEFAULT_LABEL, &request_id, "hunter3").await;
+    assert!(matches!(wrong, Err(ClientError::WrongPassword)));
+    assert_eq!(client.recovery_status("carol").await.unwrap().pending, [pending]);
+
+    let secret = client.complete_recovery("carol", util::DEFAULT_LABEL, &request_id, "hunter2").await.unwrap();
+    assert_eq!(&*secret, b"correct horse battery staple");
+    assert!(client.recovery_status("carol").await.unwrap().pending.is_empty());
+}
+
+#[tokio::test]
+async fn test_recorded_calls() {
+    let client = SkRecoveryClient::new(Recorded::new(InProcess::new(util::NUM_SERVERS)));
+    client.seed_prgs().await.unwrap();
+    client.list_secrets("bob").await.unwrap_err();
+
+    let calls = client.transport().calls();
+    assert_eq!(calls.iter().map(|call| &call.func_name[..]).collect::<Vec<_>>(), ["seed_prgs", "committee_status", "list_secrets"]);
+    assert!(calls[0].nodes.as_ref().unwrap().iter().all(|(_, error)| error.is_none()));
+    let nodes = calls[2].nodes.as_ref().unwrap();
+    assert_eq!(nodes.iter().map(|(rank, _)| *rank).collect::<Vec<_>>(), (0..util::NUM_SERVERS).collect::<Vec<_>>());
+    assert!(nodes.iter().all(|(_, error)| error.as_ref().map(|e| e.code()) == Some("unknown_user")));
+}
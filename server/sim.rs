@@ -0,0 +1,364 @@
+// Copyright 2023 The Dots Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Nodes simulated within one process, for tests. Each node runs its part of a
+// request on a thread of its own, keeps its files in a directory of its own,
+// and exchanges messages with the others through in-memory queues.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use blake2::{Blake2b512, Digest};
+use elliptic_curve::Field;
+use p256::Scalar;
+use rand::prelude::*;
+use rand_chacha::ChaCha20Rng;
+use uuid::Uuid;
+use vsss_rs::Share;
+
+use super::dots::{Env, Request};
+use super::util::{AuditEntry, Committee, RecoveryOutput, SecretKind, SecretMeta, ServerError, SkCheck, DEFAULT_LABEL, combine_shares, compute_sk_hash, field_elts_to_string, sk_to_field_elts, split_secret, string_hash_to_nzs, verify_audit_chain, verify_sk_hash};
+use super::{handle_request, now_secs, prune_hidings, secret_path, HIDINGS_DIR, INTERACTIVE, REQUEST_ID_WINDOW, ROOT};
+
+// How long a node waits for a message before giving up on the request, so
+// that a protocol bug fails the test instead of hanging it.
+const RECV_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Messages by request, sender, recipient and tag.
+type Queues = HashMap<(Uuid, usize, usize, i32), VecDeque<Vec<u8>>>;
+
+pub struct Cluster {
+    pub size: usize,
+    root: PathBuf,
+    queues: Mutex<Queues>,
+    arrived: Condvar,
+}
+
+struct SimEnv {
+    rank: usize,
+    size: usize,
+}
+
+impl Env for SimEnv {
+    fn get_world_rank(&self) -> usize {
+        self.rank
+    }
+
+    fn get_world_size(&self) -> usize {
+        self.size
+    }
+}
+
+struct SimRequest<'a> {
+    cluster: &'a Cluster,
+    rank: usize,
+    id: Uuid,
+    func_name: String,
+    args: Vec<Vec<u8>>,
+    output: Mutex<Vec<u8>>,
+}
+
+impl SimRequest<'_> {
+    // A server would run a request that is not listed as interactive on a
+    // shared worker, which must not wait on other nodes.
+    fn check_interactive(&self) -> Result<(), Box<dyn Error>> {
+        if !INTERACTIVE.contains(&self.func_name.as_str()) {
+            return Err(format!("{} exchanges messages but is not listed as interactive", self.func_name).into());
+        }
+        Ok(())
+    }
+}
+
+impl Request for SimRequest<'_> {
+    fn id(&self) -> Uuid {
+        self.id
+    }
+
+    fn func_name(&self) -> &str {
+        &self.func_name
+    }
+
+    fn args(&self) -> &[Vec<u8>] {
+        &self.args
+    }
+
+    fn output(&self, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.output.lock().map_err(|_| "output lock poisoned")?.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn msg_send(&self, buf: &[u8], recipient: usize, tag: i32) -> Result<(), Box<dyn Error>> {
+        self.check_interactive()?;
+        if recipient >= self.cluster.size {
+            return Err(format!("no node {}", recipient).into());
+        }
+        let mut queues = self.cluster.queues.lock().map_err(|_| "message queues poisoned")?;
+        queues.entry((self.id, self.rank, recipient, tag)).or_default().push_back(buf.to_vec());
+        self.cluster.arrived.notify_all();
+        Ok(())
+    }
+
+    fn msg_recv(&self, buf: &mut [u8], sender: usize, tag: i32) -> Result<usize, Box<dyn Error>> {
+        self.check_interactive()?;
+        let key = (self.id, sender, self.rank, tag);
+        let queues = self.cluster.queues.lock().map_err(|_| "message queues poisoned")?;
+        let (mut queues, timeout) = self.cluster.arrived
+            .wait_timeout_while(queues, RECV_TIMEOUT, |queues| queues.get(&key).map_or(0, VecDeque::len) == 0)
+            .map_err(|_| "message queues poisoned")?;
+        if timeout.timed_out() {
+            return Err(format!("node {} timed out waiting for node {} with tag {}", self.rank, sender, tag).into());
+        }
+        let msg = queues.get_mut(&key).and_then(VecDeque::pop_front).ok_or("message disappeared")?;
+        if msg.len() > buf.len() {
+            return Err(format!("message of {} bytes for a buffer of {}", msg.len(), buf.len()).into());
+        }
+        buf[..msg.len()].copy_from_slice(&msg);
+        Ok(msg.len())
+    }
+}
+
+impl Cluster {
+    pub fn new(size: usize) -> Cluster {
+        let root = std::env::temp_dir().join(format!("skrecovery-sim-{}", Uuid::new_v4()));
+        for rank in 0..size {
+            fs::create_dir_all(root.join(format!("node{}", rank))).unwrap();
+        }
+        Cluster { size, root, queues: Mutex::new(HashMap::new()), arrived: Condvar::new() }
+    }
+
+    /// Runs a request on the given nodes at once, each with its own
+    /// arguments, and returns what each of them output in the same order.
+    pub fn run(&self, func_name: &str, node_args: Vec<(usize, Vec<Vec<u8>>)>) -> Vec<Vec<u8>> {
+        self.run_request(Uuid::now_v7(), func_name, node_args)
+    }
+
+    /// Like `run`, under a request ID chosen by the caller.
+    pub fn run_request(&self, id: Uuid, func_name: &str, node_args: Vec<(usize, Vec<Vec<u8>>)>) -> Vec<Vec<u8>> {
+        thread::scope(|s| {
+            let nodes: Vec<_> = node_args.into_iter()
+                .map(|(rank, args)| s.spawn(move || {
+                    ROOT.with(|root| *root.borrow_mut() = self.root.join(format!("node{}", rank)));
+                    let env = SimEnv { rank, size: self.size };
+                    let req = SimRequest { cluster: self, rank, id, func_name: func_name.to_owned(), args, output: Mutex::new(Vec::new()) };
+                    handle_request(&env, &req).unwrap();
+                    req.output.into_inner().unwrap()
+                }))
+                .collect();
+            nodes.into_iter().map(|node| node.join().unwrap()).collect()
+        })
+    }
+
+    /// Runs a request with the same arguments on every node.
+    pub fn run_all(&self, func_name: &str, args: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        self.run(func_name, (0..self.size).map(|rank| (rank, args.clone())).collect())
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+// Each member's argument holding its share of the hashed password.
+fn password_args(pwd: &str, committee: &Committee, rng: &mut ChaCha20Rng) -> Vec<Vec<u8>> {
+    split_secret(*string_hash_to_nzs(pwd), committee.threshold, committee.size(), rng)
+        .iter()
+        .map(|share| share.as_ref().to_vec())
+        .collect()
+}
+
+// Splits a text secret and password the way the client does and uploads
+// them, with the salted hash given the salt and the secret's field elements.
+fn upload_text(cluster: &Cluster, committee: &Committee, user_id: &str, sk: &str, pwd: &str, rng: &mut ChaCha20Rng, skhash: impl Fn(&[u8], &[Scalar]) -> Vec<u8>) -> Vec<Vec<u8>> {
+    let sk_scalars: Vec<Scalar> = sk_to_field_elts(sk).iter().map(|nzs| *nzs.as_ref()).collect();
+    let mut sk_shares: Vec<Vec<Share<33>>> = vec![Vec::new(); committee.size()];
+    for scalar in &sk_scalars {
+        for (member, share) in split_secret(*scalar, committee.threshold, committee.size(), rng).into_iter().enumerate() {
+            sk_shares[member].push(share);
+        }
+    }
+    let salt = rng.gen::<[u8; 32]>();
+    let skhash = skhash(&salt, &sk_scalars);
+    let meta = SecretMeta { label: DEFAULT_LABEL.to_owned(), kind: SecretKind::Text, created: 0, size: sk.len() };
+    let upload_args = password_args(pwd, committee, rng).into_iter().enumerate()
+        .map(|(member, pwd_share)| (committee.members[member], vec![
+            user_id.as_bytes().to_vec(),
+            serde_json::to_vec(&sk_shares[member]).unwrap(),
+            pwd_share,
+            salt.to_vec(),
+            skhash.clone(),
+            serde_json::to_vec(&meta).unwrap(),
+        ]))
+        .collect();
+    cluster.run("upload_sk_and_pwd", upload_args)
+}
+
+// Recovers a text secret the way the client does, returning how it checked
+// against the salted hash along with the combined field elements.
+fn recover_text(cluster: &Cluster, committee: &Committee, user_id: &str, guess: &str, rng: &mut ChaCha20Rng) -> (SkCheck, Vec<Scalar>) {
+    let recover_args = password_args(guess, committee, rng).into_iter().enumerate()
+        .map(|(member, guess_share)| (committee.members[member], vec![user_id.as_bytes().to_vec(), guess_share, DEFAULT_LABEL.as_bytes().to_vec()]))
+        .collect();
+    let outputs: Vec<RecoveryOutput> = cluster.run("skrecovery", recover_args).iter()
+        .map(|output| RecoveryOutput::from_bytes(output).unwrap())
+        .collect();
+    let recovered: Vec<Scalar> = (0..outputs[0].shares.len())
+        .map(|chunk| {
+            let shares: Vec<Share<33>> = outputs.iter().map(|output| Share::try_from(output.shares[chunk].as_slice()).unwrap()).collect();
+            combine_shares(&shares, 2 * committee.threshold).unwrap()
+        })
+        .collect();
+    let salts: Vec<&[u8]> = outputs.iter().map(|output| output.salt.as_slice()).collect();
+    let hashes: Vec<&[u8]> = outputs.iter().map(|output| output.skhash.as_slice()).collect();
+    (verify_sk_hash(user_id, &salts, &hashes, &recovered), recovered)
+}
+
+#[test]
+fn test_seed_upload_and_recover() {
+    let rng = &mut ChaCha20Rng::from_entropy();
+    let committee = Committee::initial();
+    let cluster = Cluster::new(committee.size());
+    let (user_id, sk, pwd) = ("alice", "correct horse battery staple", "hunter2");
+
+    assert!(cluster.run_all("seed_prgs", vec![]).iter().all(|output| output.is_empty()));
+    let uploaded = upload_text(&cluster, &committee, user_id, sk, pwd, rng, |salt, sk_scalars| compute_sk_hash(user_id, salt, sk_scalars));
+    assert!(uploaded.iter().all(|output| output.is_empty()));
+
+    // recovers the secret with the right password, and nothing without it
+    let (check, recovered) = recover_text(&cluster, &committee, user_id, pwd, rng);
+    assert_eq!(check, SkCheck::Valid);
+    assert_eq!(*field_elts_to_string(&recovered), sk);
+    let (check, recovered) = recover_text(&cluster, &committee, user_id, "hunter3", rng);
+    assert_eq!(check, SkCheck::Invalid);
+    assert_ne!(recovered, sk_to_field_elts(sk).iter().map(|nzs| *nzs.as_ref()).collect::<Vec<Scalar>>());
+    assert!(recovered.iter().all(|scalar| !bool::from(scalar.is_zero())));
+
+    // unknown users get an error from every member
+    let guess_args: Vec<_> = password_args(pwd, &committee, rng).into_iter().enumerate()
+        .map(|(member, guess_share)| (committee.members[member], vec![b"bob".to_vec(), guess_share]))
+        .collect();
+    for output in cluster.run("skrecovery", guess_args.clone()) {
+        assert_eq!(ServerError::from_output(&output), Some(ServerError::UnknownUser("bob".to_owned())));
+    }
+
+    // the audit chains hold together, also after a node lost track of their head
+    let chains_hold = |len: usize| cluster.run_all("audit_log", vec![]).iter().all(|log| {
+        let entries: Vec<AuditEntry> = log.split(|&b| b == b'\n').filter(|line| !line.is_empty()).map(|line| serde_json::from_slice(line).unwrap()).collect();
+        entries.len() == len && verify_audit_chain(&entries).is_ok()
+    });
+    assert!(chains_hold(5));
+    fs::remove_file(cluster.root.join("node0").join("audit.head")).unwrap();
+    cluster.run("skrecovery", guess_args);
+    assert!(chains_hold(6));
+}
+
+#[test]
+fn test_recover_legacy_record() {
+    let rng = &mut ChaCha20Rng::from_entropy();
+    let committee = Committee::initial();
+    let cluster = Cluster::new(committee.size());
+    cluster.run_all("seed_prgs", vec![]);
+
+    // Uploads only take versioned hashes now, so the record is uploaded as
+    // usual and its hash replaced by one from before versioning, which only
+    // covered the salt and the secret.
+    let legacy = RefCell::new(Vec::new());
+    upload_text(&cluster, &committee, "dave", "open sesame", "hunter2", rng, |salt, sk_scalars| {
+        let mut hasher = Blake2b512::new();
+        hasher.update(salt);
+        sk_scalars.iter().for_each(|scalar| hasher.update(scalar.to_bytes()));
+        *legacy.borrow_mut() = hasher.finalize().to_vec();
+        compute_sk_hash("dave", salt, sk_scalars)
+    });
+    for rank in 0..cluster.size {
+        ROOT.with(|root| *root.borrow_mut() = cluster.root.join(format!("node{}", rank)));
+        fs::write(secret_path(&committee, "dave", DEFAULT_LABEL, "skhash.txt"), &*legacy.borrow()).unwrap();
+    }
+
+    let (check, recovered) = recover_text(&cluster, &committee, "dave", "hunter2", rng);
+    assert_eq!(check, SkCheck::Valid);
+    assert_eq!(*field_elts_to_string(&recovered), "open sesame");
+    assert_eq!(recover_text(&cluster, &committee, "dave", "hunter3", rng).0, SkCheck::Invalid);
+}
+
+#[test]
+fn test_concurrent_uploads_and_recoveries() {
+    let committee = Committee::initial();
+    let cluster = Cluster::new(committee.size());
+    cluster.run_all("seed_prgs", vec![]);
+
+    // Each thread registers its own user over and over with a new secret,
+    // recovering it after each upload while the other threads do the same.
+    let users = 8;
+    let rounds = 5;
+    thread::scope(|s| {
+        for u in 0..users {
+            let (cluster, committee) = (&cluster, &committee);
+            s.spawn(move || {
+                let rng = &mut ChaCha20Rng::from_entropy();
+                let user_id = format!("user{}", u);
+                for round in 0..rounds {
+                    let sk = format!("secret {} of {}", round, user_id);
+                    let uploaded = upload_text(cluster, committee, &user_id, &sk, "hunter2", rng, |salt, sk_scalars| compute_sk_hash(&user_id, salt, sk_scalars));
+                    assert!(uploaded.iter().all(|output| output.is_empty()));
+                    let (check, recovered) = recover_text(cluster, committee, &user_id, "hunter2", rng);
+                    assert_eq!(check, SkCheck::Valid);
+                    assert_eq!(*field_elts_to_string(&recovered), sk);
+                }
+            });
+        }
+    });
+
+    // the audit logs took every request in turn
+    for log in cluster.run_all("audit_log", vec![]) {
+        let entries: Vec<AuditEntry> = log.split(|&b| b == b'\n').filter(|line| !line.is_empty()).map(|line| serde_json::from_slice(line).unwrap()).collect();
+        assert_eq!(entries.len(), 1 + 2 * users * rounds);
+        assert!(verify_audit_chain(&entries).is_ok());
+    }
+}
+
+#[test]
+fn test_request_ids_mask_one_answer() {
+    let rng = &mut ChaCha20Rng::from_entropy();
+    let committee = Committee::initial();
+    let cluster = Cluster::new(committee.size());
+    cluster.run_all("seed_prgs", vec![]);
+    upload_text(&cluster, &committee, "alice", "open sesame", "hunter2", rng, |salt, sk_scalars| compute_sk_hash("alice", salt, sk_scalars));
+
+    let guess_args: Vec<_> = password_args("hunter3", &committee, rng).into_iter().enumerate()
+        .map(|(member, guess_share)| (committee.members[member], vec![b"alice".to_vec(), guess_share]))
+        .collect();
+    let refused = |id: Uuid| cluster.run_request(id, "skrecovery", guess_args.clone()).iter()
+        .all(|output| matches!(ServerError::from_output(output), Some(ServerError::Refused(_))));
+    let request_id = Uuid::now_v7();
+    assert!(!refused(request_id));
+    assert!(refused(request_id));
+    // and only within the window, which is all the claims have to cover
+    let stale = Uuid::new_v7(uuid::Timestamp::from_unix(uuid::NoContext, now_secs().unwrap() - REQUEST_ID_WINDOW - 1, 0));
+    assert!(refused(stale));
+    assert!(refused(Uuid::new_v4()));
+
+    let hidings = cluster.root.join("node0").join(HIDINGS_DIR);
+    fs::create_dir_all(hidings.join("1")).unwrap();
+    prune_hidings(&hidings, now_secs().unwrap()).unwrap();
+    assert!(!hidings.join("1").exists());
+    assert!(fs::read_dir(&hidings).unwrap().next().is_some());
+}